@@ -3,6 +3,144 @@ use serde::{Deserialize, Deserializer};
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
+    pub server_ip: String,
+    pub stream_port: u64,
+    pub cameras: Vec<CameraConfig>,
+    /// Solve for the robot pose with every camera that has a `robot_to_camera` at once
+    #[serde(default)]
+    pub fusion: Option<FusionConfig>,
+    /// Subscribe to the robot's gyro heading to resolve single tag ambiguity
+    #[serde(default)]
+    pub robot_heading: Option<RobotHeadingConfig>,
+    /// Where frames saved with the `/watson/<camera>/snapshot` trigger go
+    #[serde(default = "default_snapshot_dir")]
+    pub snapshot_dir: String,
+}
+
+fn default_snapshot_dir() -> String {
+    "snapshots".into()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FusionConfig {
+    /// The fused pose is published to `/watson/<name>`
+    #[serde(default = "default_fusion_name")]
+    pub name: String,
+    /// Frames from different cameras captured within this many milliseconds of each other are solved together
+    #[serde(default = "default_fusion_window_ms")]
+    pub window_ms: u64,
+}
+
+fn default_fusion_name() -> String {
+    "robot".into()
+}
+
+fn default_fusion_window_ms() -> u64 {
+    20
+}
+
+/// Options of the `photonvision` output.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PhotonVisionConfig {
+    /// Reported as the PhotonVision version. PhotonLib refuses to run against a version other than its own.
+    #[serde(default = "default_photonvision_version")]
+    pub version: String,
+}
+
+fn default_photonvision_version() -> String {
+    "v2024.3.1".into()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RobotHeadingConfig {
+    /// NetworkTables topic the robot publishes its field relative heading to, counter-clockwise positive
+    pub topic: String,
+    /// The heading is published in degrees rather than radians
+    #[serde(default)]
+    pub degrees: bool,
+    /// Headings older than this are ignored
+    #[serde(default = "default_robot_heading_max_age_ms")]
+    pub max_age_ms: u64,
+}
+
+fn default_robot_heading_max_age_ms() -> u64 {
+    100
+}
+
+impl Config {
+    /// Parses a config.json. `fiducial_size_m`, `tag_layout` and `field` may be given once at the top level, in which
+    /// case they are shared by every camera that does not set its own. Camera names must be unique.
+    ///
+    /// Cameras that don't list their `outputs` publish to `/watson/`, and also the way PhotonVision and Limelight do
+    /// if the top level has a `photonvision` object or `"limelight": true`.
+    ///
+    /// Each of a camera's `presets` only needs the keys it changes, the rest are taken from the camera.
+    pub fn parse(content: &str) -> serde_json::Result<Self> {
+        let mut value: serde_json::Value = serde_json::from_str(content)?;
+        if let Some(root) = value.as_object_mut() {
+            let shared =
+                ["fiducial_size_m", "tag_layout", "field"].map(|key| (key, root.remove(key)));
+            let mut default_outputs = vec![serde_json::json!({ "backend": "watson" })];
+            if let Some(serde_json::Value::Object(mut photonvision)) = root.remove("photonvision") {
+                photonvision.insert("backend".into(), "photonvision".into());
+                default_outputs.push(photonvision.into());
+            }
+            if let Some(serde_json::Value::Bool(true)) = root.remove("limelight") {
+                default_outputs.push(serde_json::json!({ "backend": "limelight" }));
+            }
+            if let Some(serde_json::Value::Array(cameras)) = root.get_mut("cameras") {
+                for camera in cameras.iter_mut().filter_map(|x| x.as_object_mut()) {
+                    for (key, value) in shared.iter() {
+                        if let Some(value) = value {
+                            camera.entry(*key).or_insert_with(|| value.clone());
+                        }
+                    }
+                    camera
+                        .entry("outputs")
+                        .or_insert_with(|| default_outputs.clone().into());
+                    if let Some(serde_json::Value::Array(presets)) = camera.remove("presets") {
+                        let presets = presets
+                            .into_iter()
+                            .enumerate()
+                            .map(|(i, preset)| {
+                                let mut merged = camera.clone();
+                                merged.insert("name".into(), format!("preset {}", i + 1).into());
+                                if let serde_json::Value::Object(preset) = preset {
+                                    merged.extend(preset);
+                                }
+                                // Presets are the same camera
+                                if let Some(camera_name) = camera.get("camera_name") {
+                                    merged.insert("camera_name".into(), camera_name.clone());
+                                }
+                                serde_json::Value::Object(merged)
+                            })
+                            .collect::<Vec<_>>();
+                        camera.insert("presets".into(), presets.into());
+                    }
+                }
+            }
+        }
+        let config: Self = serde_json::from_value(value)?;
+        // Streams, topics and metrics are all keyed by camera name
+        for (i, camera) in config.cameras.iter().enumerate() {
+            if config.cameras[..i]
+                .iter()
+                .any(|x| x.camera_name == camera.camera_name)
+            {
+                return Err(serde::de::Error::custom(format!(
+                    "camera_name {:?} is used by more than one camera",
+                    camera.camera_name
+                )));
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CameraConfig {
+    /// Device the `gstreamer` capture opens. Leaving it empty with that capture is a configuration error, which stops
+    /// the camera until `/restart` since trying again cannot help.
     pub video_path: String,
     pub width: u32,
     pub height: u32,
@@ -11,20 +149,272 @@ pub struct Config {
     pub gain: u32,
     pub fiducial_size_m: f64,
 
-    pub server_ip: String,
     pub camera_name: String,
-    pub stream_port: u64,
     pub has_calibration: bool,
     #[serde(deserialize_with = "deserialize_mat3")]
     pub camera_matrix: opencv::core::Mat,
     #[serde(deserialize_with = "deserialize_vecn")]
     pub distortion_coefficients: opencv::core::Mat,
     pub tag_layout: TagLayout,
+    #[serde(default)]
+    pub field: Option<FieldConfig>,
+    /// Drop tags that disagree with the others from multi-tag solves
+    #[serde(default)]
+    pub outlier_rejection: Option<OutlierRejectionConfig>,
+    /// How the standard deviations reported with each pose are computed
+    #[serde(default)]
+    pub std_devs: StdDevConfig,
+    /// Refine multi-tag solves with Levenberg-Marquardt and report their covariance
+    #[serde(default)]
+    pub refinement: Option<RefinementConfig>,
+    /// Reject implausible poses
+    #[serde(default)]
+    pub pose_filter: Option<PoseFilterConfig>,
+    /// Where the camera is mounted on the robot. Same format as a tag pose.
+    #[serde(default, deserialize_with = "deserialize_isometry3_opt")]
+    pub robot_to_camera: Option<Isometry3<f64>>,
+    /// Meters above the floor of the bottom of object targets, used to find where they are relative to the robot.
+    /// 0 for game pieces lying on the floor.
+    #[serde(default)]
+    pub target_height_m: f64,
+    /// When the robot heading is known, solve single tags for translation only
+    #[serde(default)]
+    pub heading_constrained_solve: bool,
+    /// Smooth the robot pose over time and publish it alongside the raw pose
+    #[serde(default)]
+    pub tracking: Option<TrackingConfig>,
+    /// Implementations of each stage of the pipeline, see [`crate::pipeline::registry::Registry`]
+    #[serde(default = "default_capture")]
+    pub capture: StageConfig,
+    /// Fiducial detector, `null` for cameras that only look for objects
+    #[serde(default = "default_detector")]
+    pub detector: Option<StageConfig>,
+    /// Finds game pieces and other objects, none by default
+    #[serde(default)]
+    pub object_detector: Option<StageConfig>,
+    #[serde(default = "default_pose_estimator")]
+    pub pose_estimator: StageConfig,
+    /// Where results are published. Filled in by [`Config::parse`] when missing.
+    pub outputs: Vec<StageConfig>,
+    /// Name of this pipeline configuration, reported when it is selected
+    #[serde(default = "default_preset_name")]
+    pub name: String,
+    /// Other pipeline configurations of this camera the robot can switch to. Index 0 is the camera itself, so the
+    /// first preset is index 1.
+    #[serde(default)]
+    pub presets: Vec<CameraConfig>,
+
+    /// How the MJPEG stream is encoded
+    #[serde(default)]
+    pub stream: StreamConfig,
 
     #[serde(default)]
     pub rotate180: bool,
 }
 
+fn default_preset_name() -> String {
+    "default".into()
+}
+
+impl CameraConfig {
+    /// The camera's own pipeline configuration followed by its presets
+    pub fn pipelines(&self) -> impl Iterator<Item = &CameraConfig> {
+        std::iter::once(self).chain(&self.presets)
+    }
+
+    /// Field relative pose of the robot given the field relative pose of this camera. Without a `robot_to_camera` the
+    /// camera is treated as the robot.
+    pub fn robot_pose(&self, field_to_camera: &Isometry3<f64>) -> Isometry3<f64> {
+        match self.robot_to_camera {
+            Some(robot_to_camera) => field_to_camera * robot_to_camera.inverse(),
+            None => *field_to_camera,
+        }
+    }
+}
+
+/// Picks the implementation of a pipeline stage by the name it is registered under.
+#[derive(Deserialize, Debug, Clone)]
+pub struct StageConfig {
+    pub backend: String,
+    /// Everything besides `backend`, parsed by the implementation
+    #[serde(flatten)]
+    pub options: serde_json::Map<String, serde_json::Value>,
+}
+
+impl StageConfig {
+    fn new(backend: &str) -> Self {
+        Self {
+            backend: backend.into(),
+            options: serde_json::Map::new(),
+        }
+    }
+}
+
+fn default_capture() -> StageConfig {
+    if cfg!(target_os = "linux") {
+        StageConfig::new("gstreamer")
+    } else {
+        StageConfig::new("test")
+    }
+}
+
+fn default_detector() -> Option<StageConfig> {
+    Some(StageConfig::new("aruco"))
+}
+
+fn default_pose_estimator() -> StageConfig {
+    StageConfig::new("multi_target")
+}
+
+/// How the MJPEG stream of a camera is encoded, see [`crate::pipeline::stream`].
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StreamConfig {
+    /// Draw tags, objects, the pose, FPS and latency on the stream
+    pub annotated: bool,
+    /// Pixels, the camera resolution when not set. The aspect ratio is kept when only one is set.
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    /// JPEG quality, 0 to 100
+    pub quality: i32,
+    /// Frames per second, every frame when not set
+    pub max_fps: Option<f64>,
+    /// Don't encode frames while no one is watching the stream
+    pub only_with_clients: bool,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            annotated: true,
+            width: None,
+            height: None,
+            quality: 80,
+            max_fps: None,
+            only_with_clients: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FieldConfig {
+    /// Meters along the field x axis
+    pub length_m: f64,
+    /// Meters along the field y axis
+    pub width_m: f64,
+}
+
+/// Limits on the pose of the robot, see [`crate::pipeline::pose_filter::FieldBoundsPoseFilter`]. The height and tilt
+/// are only checked when the camera has a `robot_to_camera`, since the camera itself is neither on the floor nor level.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PoseFilterConfig {
+    /// How far outside of the field the robot may be. Only checked if the field is configured.
+    pub field_margin_m: f64,
+    pub min_height_m: f64,
+    pub max_height_m: f64,
+    pub max_roll_pitch_deg: f64,
+    /// Publish implausible poses along with the reason instead of dropping them
+    pub flag_only: bool,
+}
+
+impl Default for PoseFilterConfig {
+    fn default() -> Self {
+        Self {
+            field_margin_m: 0.5,
+            min_height_m: -0.25,
+            max_height_m: 0.25,
+            max_roll_pitch_deg: 10.0,
+            flag_only: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OutlierRejectionConfig {
+    /// Pixels. Tags reprojecting worse than this are candidates for rejection. Errors are the root mean square over
+    /// both coordinates of every corner, like those of `solvePnPGeneric`.
+    #[serde(default = "default_max_reprojection_error")]
+    pub max_reprojection_error: f64,
+    /// Never reject tags once this many are left. At least 2.
+    #[serde(default = "default_min_tags")]
+    pub min_tags: usize,
+}
+
+fn default_max_reprojection_error() -> f64 {
+    5.0
+}
+
+fn default_min_tags() -> usize {
+    2
+}
+
+/// Standard deviations are `base * distance^distance_exponent / tag_count^tag_count_exponent`, grown by
+/// `error_scale` for every pixel of reprojection error and by `ambiguity_scale` for single tag ambiguity.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StdDevConfig {
+    /// x, y, z in meters and roll, pitch, yaw in radians, for one tag one meter away
+    pub base: [f64; 6],
+    pub distance_exponent: f64,
+    pub tag_count_exponent: f64,
+    pub error_scale: f64,
+    pub ambiguity_scale: f64,
+    /// Use the covariance of refined solves instead of the formula when there is one
+    pub from_covariance: bool,
+}
+
+impl Default for StdDevConfig {
+    fn default() -> Self {
+        Self {
+            base: [0.02, 0.02, 0.04, 0.06, 0.06, 0.06],
+            distance_exponent: 2.0,
+            tag_count_exponent: 1.0,
+            error_scale: 0.1,
+            ambiguity_scale: 1.0,
+            from_covariance: false,
+        }
+    }
+}
+
+/// Tuning of [`crate::pipeline::pose_tracker::PoseTracker`].
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TrackingConfig {
+    /// Standard deviation of the robot's acceleration in m/s^2
+    pub acceleration_std_dev: f64,
+    /// Standard deviation of the robot's angular acceleration in rad/s^2
+    pub angular_acceleration_std_dev: f64,
+    /// Measurements further than this many standard deviations from the prediction are dropped
+    pub gate_sigma: f64,
+    /// Start over from the next measurement after this many consecutive measurements were dropped
+    pub max_gated: usize,
+    /// Start over from the next measurement when no tags have been seen for this long
+    pub reset_timeout_ms: u64,
+}
+
+impl Default for TrackingConfig {
+    fn default() -> Self {
+        Self {
+            acceleration_std_dev: 4.0,
+            angular_acceleration_std_dev: 8.0,
+            gate_sigma: 4.0,
+            max_gated: 5,
+            reset_timeout_ms: 500,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RefinementConfig {
+    #[serde(default = "default_max_iterations")]
+    pub max_iterations: usize,
+}
+
+fn default_max_iterations() -> usize {
+    20
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct TagLayout {
     pub tags: Vec<Tag>,
@@ -79,6 +469,13 @@ where
     Ok(iso)
 }
 
+fn deserialize_isometry3_opt<'de, D>(d: D) -> Result<Option<Isometry3<f64>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_isometry3(d).map(Some)
+}
+
 fn deserialize_mat3<'de, D>(d: D) -> Result<opencv::core::Mat, D::Error>
 where
     D: Deserializer<'de>,
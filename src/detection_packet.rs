@@ -0,0 +1,198 @@
+//! Binary format of the detections published to `/watson/<camera>/detections` every frame, whether or not a pose
+//! could be solved from them.
+//!
+//! Big-endian, with the same versioning rules as [`crate::pose_packet`].
+//!
+//! | Bytes | Type       | Field                                                              |
+//! |-------|------------|--------------------------------------------------------------------|
+//! | 4     | `[u8; 4]`  | Magic, `WVDP`                                                      |
+//! | 1     | `u8`       | Version, currently 1                                               |
+//! | 8     | `u64`      | NetworkTables server time of the capture in microseconds           |
+//! | 4     | `u32`      | Detection count `n`                                                |
+//! | 80n   |            | Detections                                                         |
+//!
+//! Each detection is
+//!
+//! | Bytes | Type       | Field                                                              |
+//! |-------|------------|--------------------------------------------------------------------|
+//! | 8     | `u64`      | Tag ID, whether or not it is in the tag layout                     |
+//! | 64    | 8 × `f64`  | Pixel x, y of each corner in detection order                       |
+//! | 8     | `f64`      | Detector quality, NaN if the detector does not report one          |
+
+use binrw::{BinRead, BinResult, BinWrite, Endian};
+
+use crate::types::FiducialImageObservation;
+
+pub const MAGIC: [u8; 4] = *b"WVDP";
+pub const VERSION: u8 = 1;
+
+/// Every tag detected in one frame, see the [module documentation](self) for the layout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DetectionPacket {
+    /// Microseconds
+    pub time: u64,
+    pub detections: Vec<FiducialImageObservation>,
+}
+
+impl DetectionPacket {
+    /// Encodes the packet for publishing
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut io = std::io::Cursor::new(Vec::with_capacity(17 + 80 * self.detections.len()));
+        self.write_be(&mut io).unwrap();
+        io.into_inner()
+    }
+}
+
+impl BinWrite for DetectionPacket {
+    type Args<'a> = ();
+
+    fn write_options<W: std::io::prelude::Write + std::io::prelude::Seek>(
+        &self,
+        writer: &mut W,
+        _endian: Endian,
+        _args: Self::Args<'_>,
+    ) -> BinResult<()> {
+        MAGIC.write_be(writer)?;
+        VERSION.write_be(writer)?;
+        self.time.write_be(writer)?;
+        (self.detections.len() as u32).write_be(writer)?;
+        for detection in &self.detections {
+            detection.tag_id.write_be(writer)?;
+            for [x, y] in detection.corners {
+                x.write_be(writer)?;
+                y.write_be(writer)?;
+            }
+            detection.quality.unwrap_or(f64::NAN).write_be(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl BinRead for DetectionPacket {
+    type Args<'a> = ();
+
+    fn read_options<R: std::io::prelude::Read + std::io::prelude::Seek>(
+        reader: &mut R,
+        _endian: Endian,
+        _args: Self::Args<'_>,
+    ) -> BinResult<Self> {
+        let pos = reader.stream_position()?;
+        let magic = <[u8; 4]>::read_be(reader)?;
+        if magic != MAGIC {
+            return Err(binrw::Error::BadMagic {
+                pos,
+                found: Box::new(magic),
+            });
+        }
+        let version = u8::read_be(reader)?;
+        if version > VERSION {
+            return Err(binrw::Error::AssertFail {
+                pos: pos + 4,
+                message: format!("unsupported detection packet version {}", version),
+            });
+        }
+        let time = u64::read_be(reader)?;
+        let count = u32::read_be(reader)?;
+        let detections = (0..count)
+            .map(|_| {
+                let tag_id = u64::read_be(reader)?;
+                let [x0, y0, x1, y1, x2, y2, x3, y3] = <[f64; 8]>::read_be(reader)?;
+                let quality = f64::read_be(reader)?;
+                Ok(FiducialImageObservation {
+                    tag_id,
+                    corners: [[x0, y0], [x1, y1], [x2, y2], [x3, y3]],
+                    quality: (!quality.is_nan()).then_some(quality),
+                })
+            })
+            .collect::<BinResult<_>>()?;
+        Ok(Self { time, detections })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY: [u8; 17] = [
+        0x57, 0x56, 0x44, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x42, 0x40, 0x00, 0x00,
+        0x00, 0x00,
+    ];
+
+    const HEADER: [u8; 17] = [
+        0x57, 0x56, 0x44, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x42, 0x40, 0x00, 0x00,
+        0x00, 0x02,
+    ];
+
+    const DETECTION: [u8; 80] = [
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x40, 0x24, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x40, 0x34, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x3e, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x40, 0x34, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x3e, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x40, 0x44, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x24, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x40, 0x44, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3f, 0xe8, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const NO_QUALITY: [u8; 80] = [
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x2c, 0x3f, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x3f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x04, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x3f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x04, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x40, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3f, 0xe0, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x40, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x7f, 0xf8, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    fn round_trip(packet: DetectionPacket, expected: &[u8]) {
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes, expected);
+        let decoded = DetectionPacket::read_be(&mut std::io::Cursor::new(&bytes)).unwrap();
+        assert_eq!(decoded, packet);
+    }
+
+    #[test]
+    fn no_detections() {
+        round_trip(
+            DetectionPacket {
+                time: 1_000_000,
+                detections: Vec::new(),
+            },
+            &EMPTY,
+        );
+    }
+
+    #[test]
+    fn detections() {
+        round_trip(
+            DetectionPacket {
+                time: 1_000_000,
+                detections: vec![
+                    FiducialImageObservation {
+                        tag_id: 7,
+                        corners: [[10.0, 20.0], [30.0, 20.0], [30.0, 40.0], [10.0, 40.0]],
+                        quality: Some(0.75),
+                    },
+                    // Without a quality, which is sent as NaN
+                    FiducialImageObservation {
+                        tag_id: 300,
+                        corners: [[0.5, 1.5], [2.5, 1.5], [2.5, 3.5], [0.5, 3.5]],
+                        quality: None,
+                    },
+                ],
+            },
+            &[&HEADER[..], &DETECTION, &NO_QUALITY].concat(),
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_magic() {
+        let mut bytes = EMPTY;
+        bytes[3] = b'X';
+        assert!(DetectionPacket::read_be(&mut std::io::Cursor::new(&bytes)).is_err());
+    }
+
+    #[test]
+    fn rejects_newer_version() {
+        let mut bytes = EMPTY;
+        bytes[4] = VERSION + 1;
+        assert!(DetectionPacket::read_be(&mut std::io::Cursor::new(&bytes)).is_err());
+    }
+}
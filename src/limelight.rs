@@ -0,0 +1,267 @@
+use nalgebra::{Isometry3, Translation3, UnitQuaternion};
+use rmpv::Value;
+use serde_json::json;
+
+use crate::{
+    config::CameraConfig,
+    nt::{self, PublishProperties},
+    pipeline::targeting::TagTarget,
+    types::CameraPoseObservation,
+};
+
+/// x, y, z in meters then roll, pitch, yaw in degrees, the way Limelight reports poses
+fn pose_array(pose: &Isometry3<f64>) -> [f64; 6] {
+    let (roll, pitch, yaw) = pose.rotation.euler_angles();
+    [
+        pose.translation.x,
+        pose.translation.y,
+        pose.translation.z,
+        roll.to_degrees(),
+        pitch.to_degrees(),
+        yaw.to_degrees(),
+    ]
+}
+
+/// One frame's results in the shape of the Limelight NetworkTables API.
+#[derive(Debug, Clone)]
+pub struct LimelightResult {
+    /// Largest first, the first one is reported as the primary target
+    pub targets: Vec<TagTarget>,
+    /// Robot pose arrays with the field origin at the center, the blue alliance corner and the red alliance corner.
+    /// The first and last are only known with a field config.
+    pub botpose: Option<Vec<f64>>,
+    pub botpose_wpiblue: Option<Vec<f64>>,
+    pub botpose_wpired: Option<Vec<f64>>,
+    /// Milliseconds from the frame being captured to its results being ready
+    pub pipeline_latency_ms: f64,
+    /// Milliseconds spent capturing the frame
+    pub capture_latency_ms: f64,
+}
+
+impl LimelightResult {
+    pub fn new(
+        mut targets: Vec<TagTarget>,
+        pose: Option<&CameraPoseObservation>,
+        config_store: &CameraConfig,
+        pipeline_latency_ms: f64,
+        capture_latency_ms: f64,
+    ) -> Self {
+        targets.sort_by(|a, b| b.area.total_cmp(&a.area));
+        let mut result = Self {
+            targets,
+            botpose: None,
+            botpose_wpiblue: None,
+            botpose_wpired: None,
+            pipeline_latency_ms,
+            capture_latency_ms,
+        };
+        let Some(pose) = pose else {
+            return result;
+        };
+
+        let robot = config_store.robot_pose(&pose.pose_0);
+        let tag_poses = pose
+            .tag_ids
+            .iter()
+            .filter_map(|id| config_store.tag_layout.tags.iter().find(|x| x.id == *id))
+            .map(|x| (x.id, x.pose))
+            .collect::<Vec<_>>();
+        let distance = |from: &Isometry3<f64>, to: &Isometry3<f64>| {
+            (from.translation.vector - to.translation.vector).norm()
+        };
+        let tag_span = tag_poses
+            .iter()
+            .flat_map(|(_, a)| tag_poses.iter().map(|(_, b)| distance(a, b)))
+            .fold(0.0, f64::max);
+        let average_distance = tag_poses
+            .iter()
+            .map(|(_, x)| distance(&pose.pose_0, x))
+            .sum::<f64>()
+            / tag_poses.len().max(1) as f64;
+        let used_targets = result
+            .targets
+            .iter()
+            .filter(|x| pose.tag_ids.contains(&x.tag_id))
+            .collect::<Vec<_>>();
+        let average_area =
+            used_targets.iter().map(|x| x.area).sum::<f64>() / used_targets.len().max(1) as f64;
+        let ambiguity = match pose.error_1 {
+            Some(error_1) if error_1 > 0.0 => pose.error_0 / error_1,
+            _ => 0.0,
+        };
+
+        // Everything after the pose: latency, tag count, tag span, average distance, average area, then id, tx, ty,
+        // area, distance to camera, distance to robot and ambiguity of every tag used
+        let mut tail = vec![
+            pipeline_latency_ms + capture_latency_ms,
+            tag_poses.len() as f64,
+            tag_span,
+            average_distance,
+            average_area,
+        ];
+        for target in &used_targets {
+            let Some((_, tag_pose)) = tag_poses.iter().find(|(id, _)| *id == target.tag_id) else {
+                continue;
+            };
+            tail.extend([
+                target.tag_id as f64,
+                target.yaw,
+                target.pitch,
+                target.area,
+                distance(&pose.pose_0, tag_pose),
+                distance(&robot, tag_pose),
+                ambiguity,
+            ]);
+        }
+        let with_tail = |pose: &Isometry3<f64>| {
+            pose_array(pose)
+                .into_iter()
+                .chain(tail.iter().copied())
+                .collect::<Vec<_>>()
+        };
+
+        result.botpose_wpiblue = Some(with_tail(&robot));
+        if let Some(field) = &config_store.field {
+            let center = Translation3::new(field.length_m / 2.0, field.width_m / 2.0, 0.0);
+            result.botpose = Some(with_tail(
+                &(Isometry3::from_parts(center, UnitQuaternion::identity()).inverse() * robot),
+            ));
+            // The red alliance origin is the opposite corner, facing the other way
+            let red_origin = Isometry3::from_parts(
+                Translation3::new(field.length_m, field.width_m, 0.0),
+                UnitQuaternion::from_euler_angles(0.0, 0.0, std::f64::consts::PI),
+            );
+            result.botpose_wpired = Some(with_tail(&(red_origin.inverse() * robot)));
+        }
+        result
+    }
+
+    /// The `json` entry, in the layout `LimelightHelpers.getLatestResults` parses
+    pub fn to_json(&self, timestamp_ms: f64) -> String {
+        let fiducials = self
+            .targets
+            .iter()
+            .map(|x| {
+                json!({
+                    "fID": x.tag_id,
+                    "fam": "36H11",
+                    "tx": x.yaw,
+                    "ty": x.pitch,
+                    "txp": 0.0,
+                    "typ": 0.0,
+                    "ta": x.area,
+                    "ts": x.skew,
+                })
+            })
+            .collect::<Vec<_>>();
+        let pose = |array: &Option<Vec<f64>>| {
+            array
+                .as_ref()
+                .map_or(vec![0.0; 6], |x| x.iter().take(6).copied().collect())
+        };
+        let tail = |i: usize| self.botpose_wpiblue.as_ref().map_or(0.0, |x| x[6 + i]);
+        let valid = if self.targets.is_empty() { 0 } else { 1 };
+        json!({
+            "Results": {
+                "pID": 0.0,
+                "tl": self.pipeline_latency_ms,
+                "cl": self.capture_latency_ms,
+                "ts": timestamp_ms,
+                "v": valid,
+                "botpose": pose(&self.botpose),
+                "botpose_wpiblue": pose(&self.botpose_wpiblue),
+                "botpose_wpired": pose(&self.botpose_wpired),
+                "botpose_tagcount": tail(1),
+                "botpose_span": tail(2),
+                "botpose_avgdist": tail(3),
+                "botpose_avgarea": tail(4),
+                "Fiducial": fiducials,
+                "Retro": [],
+                "Detector": [],
+                "Classifier": [],
+                "Barcode": [],
+            }
+        })
+        .to_string()
+    }
+}
+
+/// Topics under `/limelight-<camera>/` that `LimelightHelpers` reads.
+pub struct LimelightPublisher {
+    tv: nt::PublishedTopic,
+    tx: nt::PublishedTopic,
+    ty: nt::PublishedTopic,
+    ta: nt::PublishedTopic,
+    tid: nt::PublishedTopic,
+    botpose: nt::PublishedTopic,
+    botpose_wpiblue: nt::PublishedTopic,
+    botpose_wpired: nt::PublishedTopic,
+    tl: nt::PublishedTopic,
+    cl: nt::PublishedTopic,
+    json: nt::PublishedTopic,
+}
+
+impl LimelightPublisher {
+    pub async fn new(client: &nt::Client, camera_name: &str) -> nt::Result<Self> {
+        let publish = |name: &str, r#type: nt::Type| {
+            client.publish_topic(
+                format!("/limelight-{}/{}", camera_name, name),
+                r#type,
+                Some(PublishProperties {
+                    persistent: Some(false),
+                    retained: Some(false),
+                    rest: None,
+                }),
+            )
+        };
+        Ok(Self {
+            tv: publish("tv", nt::Type::Double).await?,
+            tx: publish("tx", nt::Type::Double).await?,
+            ty: publish("ty", nt::Type::Double).await?,
+            ta: publish("ta", nt::Type::Double).await?,
+            tid: publish("tid", nt::Type::Double).await?,
+            botpose: publish("botpose", nt::Type::DoubleArray).await?,
+            botpose_wpiblue: publish("botpose_wpiblue", nt::Type::DoubleArray).await?,
+            botpose_wpired: publish("botpose_wpired", nt::Type::DoubleArray).await?,
+            tl: publish("tl", nt::Type::Double).await?,
+            cl: publish("cl", nt::Type::Double).await?,
+            json: publish("json", nt::Type::String).await?,
+        })
+    }
+
+    pub async fn publish(&self, client: &nt::Client, result: &LimelightResult) -> nt::Result<()> {
+        let best = result.targets.first();
+        for (topic, value) in [
+            (&self.tv, if best.is_some() { 1.0 } else { 0.0 }),
+            (&self.tx, best.map_or(0.0, |x| x.yaw)),
+            (&self.ty, best.map_or(0.0, |x| x.pitch)),
+            (&self.ta, best.map_or(0.0, |x| x.area)),
+            (&self.tid, best.map_or(-1.0, |x| x.tag_id as f64)),
+            (&self.tl, result.pipeline_latency_ms),
+            (&self.cl, result.capture_latency_ms),
+        ] {
+            client.publish_value(topic, &Value::F64(value)).await?;
+        }
+        // Limelight publishes zeros when it has no pose
+        for (topic, array) in [
+            (&self.botpose, &result.botpose),
+            (&self.botpose_wpiblue, &result.botpose_wpiblue),
+            (&self.botpose_wpired, &result.botpose_wpired),
+        ] {
+            let array = array.clone().unwrap_or_else(|| vec![0.0; 11]);
+            client
+                .publish_value(
+                    topic,
+                    &Value::Array(array.into_iter().map(Value::F64).collect()),
+                )
+                .await?;
+        }
+        client
+            .publish_value(
+                &self.json,
+                &Value::from(result.to_json(client.server_time() as f64 / 1000.0)),
+            )
+            .await?;
+        Ok(())
+    }
+}
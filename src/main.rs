@@ -1,110 +1,577 @@
 use std::{
-    net::{Ipv4Addr, SocketAddrV4}, str::FromStr, sync::{Arc, Mutex}, thread::JoinHandle, time::{Duration, Instant}
+    collections::HashMap, net::{Ipv4Addr, SocketAddrV4}, panic::AssertUnwindSafe, str::FromStr, sync::Arc, time::{Duration, Instant}
 };
 
-use binrw::BinWrite;
-use crossbeam_channel::{Receiver, Sender};
+use anyhow::Context;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use limelight::{LimelightPublisher, LimelightResult};
+use metrics::METRICS;
 use nt::PublishProperties;
-use once_cell::sync::Lazy;
-use opencv::types::VectorOfu8;
+use photonvision::{PhotonPipelineResult, PhotonPublisher};
+use pose_packet::PosePacket;
 use pipeline::{
-    camera_pose_estimator::CameraPoseEstimator,
-    capture::Capture,
-    fiducial_detector::{self, FiducialDetector},
+    error::{PipelineError, Recovery},
+    object_detector::ObjectTarget,
+    output::FrameResult,
+    pose_filter::PoseFilter,
+    pose_fusion::{FusionInput, MultiCameraPoseFusion},
+    pose_tracker::PoseTracker,
+    registry::{self, StageEnv},
+    stream::{annotate, StreamEncoder},
+    targeting::{tag_targets, TagTarget},
 };
-use rocket::{fairing::AdHoc, http::ContentType, response::stream::ByteStream, State};
+use rocket::{
+    fairing::AdHoc, fs::NamedFile, http::ContentType, response::stream::ByteStream, State,
+};
+use runtime::{PipelineRuntime, StageContext};
+use telemetry::{PipelineTelemetry, TelemetryPublisher};
 
 #[macro_use]
 extern crate rocket;
 
 mod config;
+pub(crate) mod detection_packet;
+pub(crate) mod limelight;
+mod metrics;
 pub(crate) mod nt;
+pub(crate) mod photonvision;
+pub(crate) mod pose_packet;
+pub(crate) mod runtime;
+pub(crate) mod snapshot;
+pub(crate) mod telemetry;
 pub(crate) mod types;
 
 pub(crate) mod pipeline {
     pub mod camera_pose_estimator;
     pub mod capture;
+    pub mod error;
     pub mod fiducial_detector;
+    pub mod object_detector;
+    pub mod output;
+    pub mod pose_filter;
+    pub mod pose_fusion;
+    pub mod pose_tracker;
+    pub mod registry;
+    pub mod reprojection;
+    pub mod retroreflective;
+    pub mod robot_heading;
+    pub mod stream;
+    pub mod targeting;
 }
 
+/// MJPEG frames of each camera, keyed by camera name.
+struct Streams(HashMap<String, Receiver<Vec<u8>>>);
+
 #[get("/")]
-fn index() -> (ContentType, &'static str) {
-    (ContentType::HTML, include_str!("index.html"))
+fn index(streams: &State<Streams>) -> (ContentType, String) {
+    let mut camera_names = streams.0.keys().collect::<Vec<_>>();
+    camera_names.sort();
+    let images = camera_names
+        .into_iter()
+        .map(|name| format!("<img src=\"stream/{}.mjpeg\" alt=\"{}\">", name, name))
+        .collect::<Vec<_>>()
+        .join("\n    ");
+    (
+        ContentType::HTML,
+        include_str!("index.html").replace("{streams}", &images),
+    )
 }
 
-#[get("/test.mjpeg")]
+#[get("/metrics")]
+fn metrics() -> (ContentType, String) {
+    (ContentType::Plain, METRICS.encode())
+}
+
+#[get("/stream/<file>")]
 async fn mjpeg_stream<'a>(
-    recv: &'a State<Receiver<Vec<u8>>>,
-) -> (
+    file: &'a str,
+    streams: &'a State<Streams>,
+) -> Option<(
     ContentType,
     ByteStream<impl futures_util::Stream<Item = Vec<u8>> + 'a>,
-) {
-    (
+)> {
+    let camera_name = file.strip_suffix(".mjpeg")?;
+    let recv = streams.0.get(camera_name)?;
+    Some((
         ContentType::new("multipart", "x-mixed-replace; boundary=FRAME"),
         ByteStream! {
+            let _client = metrics::StreamClient::new(camera_name);
             loop {
                 yield recv.recv().unwrap();
             }
         },
-    )
+    ))
+}
+
+/// Messages to the NetworkTables thread. Camera specific messages carry the index of the camera.
+enum NtData {
+    Pose(usize, Vec<u8>),
+    FilteredPose(usize, Vec<u8>),
+    Detections(usize, Vec<u8>),
+    Targets(usize, Vec<TagTarget>),
+    Objects(usize, Vec<ObjectTarget>),
+    Photon(usize, PhotonPipelineResult),
+    Limelight(usize, LimelightResult),
+    Telemetry(usize, PipelineTelemetry),
+    /// Index and name of the pipeline a camera switched to, sent right away unlike the rest of the telemetry
+    ActivePipeline(usize, u64, String),
+    FusedPose(Vec<u8>),
+    /// A snapshot asked for over NetworkTables was saved
+    SnapshotSaved(usize),
+}
+
+/// Topics published for a single camera.
+struct CameraTopics {
+    pose: nt::PublishedTopic,
+    /// Only published when tracking is enabled for the camera
+    filtered_pose: Option<nt::PublishedTopic>,
+    /// Every tag detected, whether or not it is in the layout
+    detections: nt::PublishedTopic,
+    /// IDs of the tags in view
+    target_ids: nt::PublishedTopic,
+    /// Created the first time each tag is seen
+    targets: HashMap<u64, nt::PublishedTopic>,
+    /// Only published when the camera has an object detector
+    objects: Option<ObjectTopics>,
+    telemetry: TelemetryPublisher,
+    /// Only published in PhotonVision output mode
+    photon: Option<PhotonPublisher>,
+    /// Only published in Limelight output mode
+    limelight: Option<LimelightPublisher>,
+    /// Set back to false once the snapshot the robot asked for is saved
+    snapshot: nt::PublishedTopic,
+}
+
+/// Parallel double arrays of every object target of a camera, see [`ObjectTarget`].
+struct ObjectTopics {
+    /// [`ObjectTarget::to_array`] of each target
+    targets: nt::PublishedTopic,
+    /// [`ObjectTarget::corners_array`] of each target
+    corners: nt::PublishedTopic,
+    /// [`ObjectTarget::pose_array`] of each target
+    poses: nt::PublishedTopic,
+    /// [`ObjectTarget::class_name`] of each target, empty for targets without one
+    classes: nt::PublishedTopic,
 }
 
-static APRILTAG_THREAD_STOP: Lazy<Arc<Mutex<bool>>> = Lazy::new(|| Arc::new(Mutex::new(false)));
-static APRILTAG_THREAD_JOINHANDLE: Lazy<Arc<tokio::sync::Mutex<Option<JoinHandle<()>>>>> =
-    Lazy::new(|| Arc::new(tokio::sync::Mutex::new(None)));
+impl ObjectTopics {
+    async fn new(client: &nt::Client, camera_name: &str) -> nt::Result<Self> {
+        let publish = |suffix: &str| {
+            client.publish_topic(
+                format!("/watson/{}/objects{}", camera_name, suffix),
+                nt::Type::DoubleArray,
+                Some(PublishProperties {
+                    persistent: Some(false),
+                    retained: Some(false),
+                    rest: None,
+                }),
+            )
+        };
+        Ok(Self {
+            targets: publish("").await?,
+            corners: publish("/corners").await?,
+            poses: publish("/poses").await?,
+            classes: client
+                .publish_topic(
+                    format!("/watson/{}/objects/classes", camera_name),
+                    nt::Type::StringArray,
+                    Some(PublishProperties {
+                        persistent: Some(false),
+                        retained: Some(false),
+                        rest: None,
+                    }),
+                )
+                .await?,
+        })
+    }
+
+    async fn publish(&self, client: &nt::Client, objects: &[ObjectTarget]) -> nt::Result<()> {
+        let flatten = |values: Vec<f64>| {
+            rmpv::Value::Array(values.into_iter().map(rmpv::Value::F64).collect())
+        };
+        client
+            .publish_value(
+                &self.targets,
+                &flatten(objects.iter().flat_map(|x| x.to_array()).collect()),
+            )
+            .await?;
+        client
+            .publish_value(
+                &self.corners,
+                &flatten(objects.iter().flat_map(|x| x.corners_array()).collect()),
+            )
+            .await?;
+        client
+            .publish_value(
+                &self.poses,
+                &flatten(objects.iter().flat_map(|x| x.pose_array()).collect()),
+            )
+            .await?;
+        client
+            .publish_value(
+                &self.classes,
+                &rmpv::Value::Array(
+                    objects
+                        .iter()
+                        .map(|x| rmpv::Value::from(x.class_name.as_deref().unwrap_or("")))
+                        .collect(),
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+}
 
-static NT_TIME: Lazy<Arc<Mutex<(u32, Instant)>>> = Lazy::new(|| Arc::new(Mutex::new((0, Instant::now()))));
+/// Context of the errors of [`nt_thread`] that come from not reaching the server, as opposed to errors of an established
+/// connection
+#[derive(Debug, thiserror::Error)]
+#[error("can't connect to NetworkTables")]
+pub struct NtConnectError;
 
-async fn nt_thread(data_recv: &Receiver<Vec<u8>>, config_content: &str) -> anyhow::Result<()> {
-    let config: config::Config = serde_json::from_str(config_content)?;
+async fn nt_thread(context: &StageContext, data_recv: &Receiver<NtData>) -> anyhow::Result<()> {
+    let config = config::Config::parse(&context.config_content)?;
     let server_ip = config.server_ip;
-    let name = config.camera_name.clone();
-    let client =
-        nt::Client::try_new(SocketAddrV4::new(Ipv4Addr::from_str(&server_ip)?, 5810)).await?;
-    let publisher = client
-        .publish_topic(
-            format!("/CameraPublisher/{}/streams", name),
-            nt::Type::StringArray,
-            Some(PublishProperties {
-                persistent: Some(false),
-                retained: Some(true),
-                rest: None,
+    let camera_names = config
+        .cameras
+        .iter()
+        .map(|x| x.camera_name.clone())
+        .collect::<Vec<_>>();
+    let reconnect_names = camera_names.clone();
+    let client = nt::Client::try_new_w_config(
+        SocketAddrV4::new(Ipv4Addr::from_str(&server_ip)?, 5810),
+        nt::Config {
+            on_reconnect: Box::new(move || {
+                for name in &reconnect_names {
+                    METRICS.nt_reconnects.with_label_values(&[name]).inc();
+                }
+                Box::pin(async {})
             }),
-        )
-        .await?;
+            ..Default::default()
+        },
+    )
+    .await
+    .context(NtConnectError)?;
     let my_local_ip = local_ip_address::local_ip()?.to_string();
-    client
-        .publish_value(
-            &publisher,
-            &rmpv::Value::Array(vec![rmpv::Value::String(
-                format!(
-                    "mjpeg:http://{}:{}/test.mjpeg",
-                    my_local_ip, config.stream_port
+    let mut topics = Vec::with_capacity(camera_names.len());
+    for (camera, name) in camera_names.iter().enumerate() {
+        let publisher = client
+            .publish_topic(
+                format!("/CameraPublisher/{}/streams", name),
+                nt::Type::StringArray,
+                Some(PublishProperties {
+                    persistent: Some(false),
+                    retained: Some(true),
+                    rest: None,
+                }),
+            )
+            .await?;
+        client
+            .publish_value(
+                &publisher,
+                &rmpv::Value::Array(vec![rmpv::Value::String(
+                    format!(
+                        "mjpeg:http://{}:{}/stream/{}.mjpeg",
+                        my_local_ip, config.stream_port, name
+                    )
+                    .into(),
+                )]),
+            )
+            .await?;
+        let pose = client
+            .publish_topic(
+                format!("/watson/{}", name),
+                nt::Type::Raw,
+                Some(PublishProperties {
+                    persistent: Some(false),
+                    retained: Some(false),
+                    rest: None,
+                }),
+            )
+            .await?;
+        let has_tracking = config.cameras[camera]
+            .pipelines()
+            .any(|x| x.tracking.is_some());
+        let filtered_pose = if has_tracking {
+            Some(
+                client
+                    .publish_topic(
+                        format!("/watson/{}/filtered", name),
+                        nt::Type::Raw,
+                        Some(PublishProperties {
+                            persistent: Some(false),
+                            retained: Some(false),
+                            rest: None,
+                        }),
+                    )
+                    .await?,
+            )
+        } else {
+            None
+        };
+        let detections = client
+            .publish_topic(
+                format!("/watson/{}/detections", name),
+                nt::Type::Raw,
+                Some(PublishProperties {
+                    persistent: Some(false),
+                    retained: Some(false),
+                    rest: None,
+                }),
+            )
+            .await?;
+        let target_ids = client
+            .publish_topic(
+                format!("/watson/{}/targets/ids", name),
+                nt::Type::IntArray,
+                Some(PublishProperties {
+                    persistent: Some(false),
+                    retained: Some(false),
+                    rest: None,
+                }),
+            )
+            .await?;
+        let has_objects = config.cameras[camera]
+            .pipelines()
+            .any(|x| x.object_detector.is_some());
+        let objects = if has_objects {
+            Some(ObjectTopics::new(&client, name).await?)
+        } else {
+            None
+        };
+        let photon_version = config.cameras[camera]
+            .pipelines()
+            .flat_map(|x| &x.outputs)
+            .find(|x| x.backend == "photonvision")
+            .map(registry::options::<config::PhotonVisionConfig>)
+            .transpose()?
+            .map(|x| x.version);
+        let has_limelight = config.cameras[camera]
+            .pipelines()
+            .flat_map(|x| &x.outputs)
+            .any(|x| x.backend == "limelight");
+        let photon = match photon_version {
+            Some(version) => {
+                let calibration = photonvision::calibration(&config.cameras[camera])?;
+                Some(PhotonPublisher::new(&client, name, &version, calibration).await?)
+            }
+            None => None,
+        };
+        let limelight = if has_limelight {
+            Some(LimelightPublisher::new(&client, name).await?)
+        } else {
+            None
+        };
+        let snapshot = client
+            .publish_topic(
+                format!("/watson/{}/snapshot", name),
+                nt::Type::Boolean,
+                Some(PublishProperties {
+                    persistent: Some(false),
+                    retained: Some(false),
+                    rest: None,
+                }),
+            )
+            .await?;
+        topics.push(CameraTopics {
+            pose,
+            filtered_pose,
+            detections,
+            target_ids,
+            targets: HashMap::new(),
+            objects,
+            telemetry: TelemetryPublisher::new(&client, name).await?,
+            photon,
+            limelight,
+            snapshot,
+        });
+    }
+    let fused_publisher = match &config.fusion {
+        Some(fusion) => Some(
+            client
+                .publish_topic(
+                    format!("/watson/{}", fusion.name),
+                    nt::Type::Raw,
+                    Some(PublishProperties {
+                        persistent: Some(false),
+                        retained: Some(false),
+                        rest: None,
+                    }),
                 )
-                .into(),
-            )]),
-        )
-        .await?;
-    let publisher = client
-        .publish_topic(
-            format!("/watson/{}", name),
-            nt::Type::Raw,
-            Some(PublishProperties {
-                persistent: Some(false),
-                retained: Some(false),
-                rest: None,
-            }),
-        )
-        .await?;
+                .await?,
+        ),
+        None => None,
+    };
+    if let (Some(heading_config), Some(robot_heading)) =
+        (&config.robot_heading, &context.robot_heading)
+    {
+        let mut subscription = client.subscribe(&[&heading_config.topic]).await?;
+        let degrees = heading_config.degrees;
+        let robot_heading = robot_heading.clone();
+        tokio::spawn(async move {
+            while let Some(message) = subscription.next().await {
+                let Some(heading) = message
+                    .data
+                    .as_f64()
+                    .or_else(|| message.data.as_i64().map(|x| x as f64))
+                else {
+                    continue;
+                };
+                robot_heading.set(if degrees {
+                    heading.to_radians()
+                } else {
+                    heading
+                });
+            }
+        });
+    }
+    {
+        let snapshot_topics = camera_names
+            .iter()
+            .map(|name| format!("/watson/{}/snapshot", name))
+            .collect::<Vec<_>>();
+        let mut subscription = client.subscribe(&snapshot_topics).await?;
+        let snapshots = context.snapshots.clone();
+        tokio::spawn(async move {
+            while let Some(message) = subscription.next().await {
+                // Every true saves a frame, which is acknowledged by setting it back to false
+                if let (Some(camera), Some(true)) = (
+                    snapshot_topics.iter().position(|x| *x == message.topic_name),
+                    message.data.as_bool(),
+                ) {
+                    snapshots.save(camera);
+                }
+            }
+        });
+    }
+    if config.cameras.iter().any(|x| !x.presets.is_empty()) {
+        let request_topics = camera_names
+            .iter()
+            .map(|name| format!("/watson/{}/pipeline_request", name))
+            .collect::<Vec<_>>();
+        let mut subscription = client.subscribe(&request_topics).await?;
+        let presets = context.presets.clone();
+        tokio::spawn(async move {
+            while let Some(message) = subscription.next().await {
+                let (Some(camera), Some(index)) = (
+                    request_topics.iter().position(|x| *x == message.topic_name),
+                    message
+                        .data
+                        .as_u64()
+                        .or_else(|| message.data.as_f64().map(|x| x as u64)),
+                ) else {
+                    continue;
+                };
+                presets.request(camera, index as usize);
+            }
+        });
+    }
     loop {
-        match APRILTAG_THREAD_STOP.lock().map(|x| *x) {
-            Ok(false) => {}
-            _ => break Ok(()),
+        if context.stopped() {
+            break Ok(());
         }
-        *NT_TIME.lock().unwrap() = (client.server_time(), Instant::now());
-        let data = data_recv.recv()?;
-        let data = rmpv::Value::Binary(data);
-        let fut = client.publish_value(&publisher, &data);
+        context.clock.sync(client.server_time());
+        // Time out regularly to notice being stopped
+        let data = match data_recv.recv_timeout(Duration::from_millis(100)) {
+            Ok(data) => data,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let fut = async {
+            match data {
+                NtData::Pose(camera, data) => {
+                    client
+                        .publish_value(&topics[camera].pose, &rmpv::Value::Binary(data))
+                        .await?
+                }
+                NtData::FilteredPose(camera, data) => {
+                    if let Some(publisher) = &topics[camera].filtered_pose {
+                        client
+                            .publish_value(publisher, &rmpv::Value::Binary(data))
+                            .await?
+                    }
+                }
+                NtData::Detections(camera, data) => {
+                    client
+                        .publish_value(&topics[camera].detections, &rmpv::Value::Binary(data))
+                        .await?
+                }
+                NtData::Targets(camera, targets) => {
+                    let topics = &mut topics[camera];
+                    client
+                        .publish_value(
+                            &topics.target_ids,
+                            &rmpv::Value::Array(
+                                targets.iter().map(|x| rmpv::Value::from(x.tag_id)).collect(),
+                            ),
+                        )
+                        .await?;
+                    for target in targets {
+                        if !topics.targets.contains_key(&target.tag_id) {
+                            let topic = client
+                                .publish_topic(
+                                    format!(
+                                        "/watson/{}/targets/{}",
+                                        camera_names[camera], target.tag_id
+                                    ),
+                                    nt::Type::DoubleArray,
+                                    Some(PublishProperties {
+                                        persistent: Some(false),
+                                        retained: Some(false),
+                                        rest: None,
+                                    }),
+                                )
+                                .await?;
+                            topics.targets.insert(target.tag_id, topic);
+                        }
+                        client
+                            .publish_value(
+                                &topics.targets[&target.tag_id],
+                                &rmpv::Value::Array(
+                                    target.to_array().map(rmpv::Value::F64).to_vec(),
+                                ),
+                            )
+                            .await?;
+                    }
+                }
+                NtData::Objects(camera, objects) => {
+                    if let Some(publisher) = &topics[camera].objects {
+                        publisher.publish(&client, &objects).await?
+                    }
+                }
+                NtData::Photon(camera, result) => {
+                    if let Some(photon) = &mut topics[camera].photon {
+                        photon.publish(&client, &result).await?
+                    }
+                }
+                NtData::Limelight(camera, result) => {
+                    if let Some(limelight) = &topics[camera].limelight {
+                        limelight.publish(&client, &result).await?
+                    }
+                }
+                NtData::Telemetry(camera, telemetry) => {
+                    topics[camera].telemetry.publish(&client, &telemetry).await?
+                }
+                NtData::ActivePipeline(camera, pipeline, pipeline_name) => {
+                    topics[camera]
+                        .telemetry
+                        .publish_pipeline(&client, pipeline, &pipeline_name)
+                        .await?
+                }
+                NtData::FusedPose(data) => {
+                    if let Some(publisher) = &fused_publisher {
+                        client
+                            .publish_value(publisher, &rmpv::Value::Binary(data))
+                            .await?
+                    }
+                }
+                NtData::SnapshotSaved(camera) => {
+                    client
+                        .publish_value(&topics[camera].snapshot, &rmpv::Value::Boolean(false))
+                        .await?
+                }
+            }
+            anyhow::Ok(())
+        };
         tokio::select! {
             res = fut => {
                 res?;
@@ -116,6 +583,427 @@ async fn nt_thread(data_recv: &Receiver<Vec<u8>>, config_content: &str) -> anyho
     }
 }
 
+fn millis_between(start: Instant, end: Instant) -> f64 {
+    end.duration_since(start).as_secs_f64() * 1000.0
+}
+
+/// How often each camera publishes its telemetry. Every frame would crowd out the poses on the NetworkTables channel.
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sends `telemetry` unless it was already sent within the last [`TELEMETRY_INTERVAL`].
+fn send_telemetry(
+    data_send: &Sender<NtData>,
+    camera: usize,
+    telemetry: &PipelineTelemetry,
+    last_sent: &mut Option<Instant>,
+) {
+    if last_sent.map_or(true, |x| x.elapsed() >= TELEMETRY_INTERVAL)
+        && data_send
+            .try_send(NtData::Telemetry(camera, telemetry.clone()))
+            .is_ok()
+    {
+        *last_sent = Some(Instant::now());
+    }
+}
+
+fn apriltag_thread(
+    camera: usize,
+    context: StageContext,
+    data_send: Sender<NtData>,
+    send: Sender<Vec<u8>>,
+    fusion_send: Option<Sender<FusionInput>>,
+) {
+    let camera_name = config::Config::parse(&context.config_content).unwrap().cameras[camera]
+        .camera_name
+        .clone();
+    // Restarts in a row after a panic
+    let mut panics = 0;
+    while !context.stopped() {
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut camera_config = config::Config::parse(&context.config_content)
+                .unwrap()
+                .cameras
+                .swap_remove(camera);
+            let presets = std::mem::take(&mut camera_config.presets);
+            let pipelines = std::iter::once(camera_config)
+                .chain(presets)
+                .collect::<Vec<_>>();
+            let env = StageEnv {
+                robot_heading: context.robot_heading.clone(),
+            };
+            let mut active = context.presets.requested(camera);
+            let mut pipeline_stages = match context.registry.preset_stages(&pipelines, active, &env) {
+                Ok(pipeline_stages) => pipeline_stages,
+                Err(e) => {
+                    match context.supervisor.recover(&camera_name, &e, 1) {
+                        // Fall back to the camera's own pipeline before giving up
+                        Recovery::Stop if active != 0 => {
+                            context.presets.request(camera, 0);
+                        }
+                        Recovery::Stop => return Err(e),
+                        Recovery::Reopen(delay) | Recovery::Backoff(delay) => {
+                            std::thread::sleep(delay)
+                        }
+                    }
+                    return Ok(());
+                }
+            };
+            context.presets.set_active(camera, active);
+            let mut pose_filter = pipeline::pose_filter::FieldBoundsPoseFilter;
+            let mut pose_tracker = PoseTracker::default();
+            let mut stream_encoder = StreamEncoder::default();
+            let mut telemetry = PipelineTelemetry {
+                pipeline: active as u64,
+                pipeline_name: pipelines[active].name.clone(),
+                ..Default::default()
+            };
+            let mut last_system_sample: Option<Instant> = None;
+            let mut last_telemetry: Option<Instant> = None;
+            let mut start = Instant::now();
+            // Frames in a row that failed
+            let mut failures = 0;
+            while !context.stopped() {
+                if last_system_sample.map_or(true, |x| x.elapsed() > Duration::from_secs(1)) {
+                    telemetry.sample_system();
+                    last_system_sample = Some(Instant::now());
+                }
+                let requested = context.presets.requested(camera);
+                if requested != active {
+                    match pipeline_stages.switch(&pipelines, requested) {
+                        Ok(()) => {
+                            active = requested;
+                            context.presets.set_active(camera, active);
+                            telemetry.pipeline = active as u64;
+                            telemetry.pipeline_name = pipelines[active].name.clone();
+                            _ = data_send.send_timeout(
+                                NtData::ActivePipeline(camera, telemetry.pipeline, telemetry.pipeline_name.clone()),
+                                Duration::from_millis(4),
+                            );
+                        }
+                        Err(e) => {
+                            telemetry.last_error = Some(e.to_string());
+                            context.supervisor.report(&camera_name, e);
+                            // Stay on the current pipeline instead of trying again every frame
+                            context.presets.request(camera, active);
+                        }
+                    }
+                }
+                let config = &pipelines[active];
+                let stages = pipeline_stages.stages();
+                let result = (|| -> Result<(), PipelineError> {
+                    let capture_start = Instant::now();
+                    // Server time of the frame, so consumers can subtract the latency once
+                    let capture_time = context.clock.now();
+                    let mut frame = stages.capture.get_frame(config)?;
+                    let detect_start = Instant::now();
+                    telemetry.capture_latency_ms = millis_between(capture_start, detect_start);
+                    let objects = match &mut stages.object_detector {
+                        Some(detector) => Some(detector.detect_objects(&frame, config)?),
+                        None => None,
+                    };
+                    let tags = match &mut stages.detector {
+                        Some(detector) => detector.detect_fiducial(&frame, config)?,
+                        None => Vec::new(),
+                    };
+                    let solve_start = Instant::now();
+                    telemetry.detect_latency_ms = millis_between(detect_start, solve_start);
+                    telemetry.tags_seen = tags.len() as u64;
+                    for tag in &tags {
+                        METRICS
+                            .detections
+                            .with_label_values(&[&config.camera_name, &tag.tag_id.to_string()])
+                            .inc();
+                    }
+                    let targets = tag_targets(&tags, config)?;
+                    let fusion_observations = fusion_send.as_ref().map(|_| tags.clone());
+                    let pose = stages
+                        .pose_estimator
+                        .solve_camera_pose(tags.clone(), config)?
+                        .and_then(|mut pose| match pose_filter.filter_pose(&mut pose, config) {
+                            Ok(()) => {
+                                telemetry.last_rejection = None;
+                                Some(pose)
+                            }
+                            Err(rejection) => {
+                                telemetry.last_rejection = Some(rejection.to_string());
+                                if config.pose_filter.as_ref().is_some_and(|x| x.flag_only) {
+                                    pose.rejection = Some(rejection);
+                                    Some(pose)
+                                } else {
+                                    telemetry.rejected_poses += 1;
+                                    None
+                                }
+                            }
+                        });
+                    let encode_start = Instant::now();
+                    telemetry.solve_latency_ms = millis_between(solve_start, encode_start);
+                    if let Some(pose) = &pose {
+                        METRICS
+                            .pnp_error
+                            .with_label_values(&[&config.camera_name])
+                            .observe(pose.error_0);
+                    }
+                    let filtered_pose = match (&config.tracking, &pose) {
+                        (Some(tracking), Some(pose)) if pose.rejection.is_none() => {
+                            pose_tracker.update(pose, capture_start, tracking, config)
+                        }
+                        _ => None,
+                    };
+                    let frame_result = FrameResult {
+                        camera,
+                        time: capture_time,
+                        captured: capture_start,
+                        detected: detect_start,
+                        capture_latency_ms: telemetry.capture_latency_ms,
+                        observations: &tags,
+                        targets: &targets,
+                        objects: objects.as_deref(),
+                        pose: pose.as_ref(),
+                        filtered_pose: filtered_pose.as_ref(),
+                        config,
+                    };
+                    for output in &mut stages.outputs {
+                        match output.output(&frame_result) {
+                            Ok(messages) => {
+                                for message in messages {
+                                    _ = data_send.send_timeout(message, Duration::from_millis(4));
+                                }
+                            }
+                            Err(e) => context.supervisor.report(&camera_name, e),
+                        }
+                    }
+                    if let (Some(fusion_send), Some(observations)) = (&fusion_send, fusion_observations) {
+                        _ = fusion_send.try_send(FusionInput {
+                            camera,
+                            received: capture_start,
+                            time: capture_time,
+                            observations,
+                            camera_pose: pose
+                                .as_ref()
+                                .filter(|x| x.rejection.is_none())
+                                .map(|x| (x.pose_0, x.error_0)),
+                        });
+                    }
+
+                    // Before the frame is annotated for the stream
+                    if let Some(request) = context.snapshots.take(camera) {
+                        let fps = telemetry.fps;
+                        match context.snapshots.fulfill(request, &frame, &frame_result, fps) {
+                            Ok(true) => {
+                                _ = data_send.send_timeout(NtData::SnapshotSaved(camera), Duration::from_millis(4));
+                            }
+                            Ok(false) => {}
+                            Err(e) => context.supervisor.report(&camera_name, e),
+                        }
+                    }
+                    if stream_encoder.wants_frame(&config.stream, &config.camera_name) {
+                        let debug_image = stages.object_detector.as_ref().and_then(|x| x.debug_image());
+                        let stream_image = match debug_image {
+                            Some(debug_image) => debug_image,
+                            None => {
+                                if config.stream.annotated {
+                                    annotate(&mut frame, &frame_result, telemetry.fps)?;
+                                }
+                                &frame
+                            }
+                        };
+                        let data = stream_encoder.encode(stream_image, &config.stream)?;
+                        if send.send_timeout(data, Duration::from_millis(8)).is_err() {
+                            METRICS
+                                .dropped_stream_frames
+                                .with_label_values(&[&config.camera_name])
+                                .inc();
+                        }
+                    }
+
+                    let next = Instant::now();
+                    telemetry.encode_latency_ms = millis_between(encode_start, next);
+                    telemetry.fps = 1.0 / next.duration_since(start).as_secs_f64();
+                    METRICS
+                        .frame_time
+                        .with_label_values(&[&config.camera_name])
+                        .observe(next.duration_since(start).as_secs_f64());
+                    telemetry.heartbeat += 1;
+                    start = next;
+                    send_telemetry(&data_send, camera, &telemetry, &mut last_telemetry);
+                    Ok(())
+                })();
+                match result {
+                    Ok(()) => failures = 0,
+                    Err(e) => {
+                        failures += 1;
+                        if let PipelineError::DroppedFrame = e {
+                            telemetry.dropped_frames += 1;
+                        }
+                        telemetry.last_error = Some(e.to_string());
+                        send_telemetry(&data_send, camera, &telemetry, &mut last_telemetry);
+                        match context.supervisor.recover(&camera_name, &e, failures) {
+                            Recovery::Reopen(delay) => {
+                                stages.capture.reopen();
+                                std::thread::sleep(delay);
+                            }
+                            Recovery::Backoff(delay) => std::thread::sleep(delay),
+                            Recovery::Stop => return Err(e),
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }));
+        match result {
+            Ok(Ok(())) => panics = 0,
+            // Already reported by the supervisor
+            Ok(Err(_)) => break,
+            Err(e) => {
+                let message = e
+                    .downcast_ref::<&str>()
+                    .map(|x| x.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "camera pipeline panicked".into());
+                panics += 1;
+                match context.supervisor.recover(&camera_name, &PipelineError::Panic(message), panics) {
+                    Recovery::Reopen(delay) | Recovery::Backoff(delay) => std::thread::sleep(delay),
+                    Recovery::Stop => break,
+                }
+            }
+        }
+    }
+}
+
+fn fusion_thread(context: StageContext, data_send: Sender<NtData>, fusion_recv: Receiver<FusionInput>) {
+    let config = config::Config::parse(&context.config_content).unwrap();
+    let Some(fusion_config) = config.fusion else {
+        return;
+    };
+    let window = Duration::from_millis(fusion_config.window_ms);
+    let fused_cameras = config
+        .cameras
+        .iter()
+        .filter(|x| x.robot_to_camera.is_some())
+        .count();
+    let mut fusion = MultiCameraPoseFusion::default();
+    while !context.stopped() {
+        let Ok(first) = fusion_recv.recv_timeout(Duration::from_millis(100)) else {
+            continue;
+        };
+        let deadline = first.received + window;
+        let mut inputs = vec![first];
+        while inputs.len() < fused_cameras {
+            let Ok(input) = fusion_recv.recv_deadline(deadline) else {
+                break;
+            };
+            // Only keep the newest frame from each camera
+            inputs.retain(|x| x.camera != input.camera);
+            inputs.push(input);
+        }
+        let pose = match fusion.fuse(&inputs, &config.cameras) {
+            Ok(pose) => pose,
+            Err(e) => {
+                context.supervisor.report("fusion", e);
+                continue;
+            }
+        };
+        if let Some(pose) = pose {
+            let time = (inputs.iter().map(|x| x.time as u64).sum::<u64>() / inputs.len() as u64) as u32;
+            let captured = inputs.iter().map(|x| x.received).min().unwrap();
+            match PosePacket::new(&pose, time, Some(millis_between(captured, Instant::now()))) {
+                Ok(packet) => {
+                    _ = data_send.send_timeout(NtData::FusedPose(packet.to_bytes()), Duration::from_millis(4));
+                }
+                Err(e) => context.supervisor.report("fusion", e),
+            }
+        }
+    }
+}
+
+#[post("/restart")]
+async fn restart(runtime: &State<Arc<PipelineRuntime>>) -> &'static str {
+    runtime.restart().await;
+    "restarted"
+}
+
+/// The pipeline configurations of a camera and which one is running
+#[get("/pipeline/<camera_name>")]
+fn pipeline_status(
+    camera_name: &str,
+    runtime: &State<Arc<PipelineRuntime>>,
+) -> Option<(ContentType, String)> {
+    let camera = runtime.camera_index(camera_name)?;
+    let presets = runtime.presets();
+    Some((
+        ContentType::JSON,
+        serde_json::json!({
+            "active": presets.active(camera),
+            "requested": presets.requested(camera),
+            "names": presets.names(camera),
+        })
+        .to_string(),
+    ))
+}
+
+/// Switches a camera to the pipeline configuration with the given index or name
+#[post("/pipeline/<camera_name>/<pipeline>")]
+fn select_pipeline(
+    camera_name: &str,
+    pipeline: &str,
+    runtime: &State<Arc<PipelineRuntime>>,
+) -> Option<&'static str> {
+    let camera = runtime.camera_index(camera_name)?;
+    let presets = runtime.presets();
+    let index = pipeline
+        .parse::<usize>()
+        .ok()
+        .or_else(|| presets.names(camera).iter().position(|x| x == pipeline))?;
+    presets.request(camera, index).then_some("selected")
+}
+
+/// The next frame of a camera, of the first camera if none is given. Raw unless `annotated` is set.
+#[get("/snapshot.jpg?<camera>&<annotated>")]
+async fn snapshot_image(
+    camera: Option<&str>,
+    annotated: Option<bool>,
+    runtime: &State<Arc<PipelineRuntime>>,
+) -> Option<(ContentType, Vec<u8>)> {
+    let camera = match camera {
+        Some(camera_name) => runtime.camera_index(camera_name)?,
+        None => 0,
+    };
+    let recv = runtime
+        .snapshots()
+        .request(camera, annotated.unwrap_or(false))?;
+    let data = tokio::task::spawn_blocking(move || recv.recv_timeout(Duration::from_secs(2)))
+        .await
+        .ok()?
+        .ok()?;
+    Some((ContentType::JPEG, data))
+}
+
+/// Names of the saved snapshots and their JSON sidecars, newest first
+#[get("/snapshots")]
+fn snapshots(runtime: &State<Arc<PipelineRuntime>>) -> (ContentType, String) {
+    (
+        ContentType::JSON,
+        serde_json::json!(runtime.snapshots().list()).to_string(),
+    )
+}
+
+#[get("/snapshots/<file>")]
+async fn snapshot_file(file: &str, runtime: &State<Arc<PipelineRuntime>>) -> Option<NamedFile> {
+    // Only files directly in the snapshot directory
+    if file.starts_with('.') || file.contains(['/', '\\']) {
+        return None;
+    }
+    NamedFile::open(runtime.snapshots().directory().join(file))
+        .await
+        .ok()
+}
+
+/// Errors of every pipeline stage
+#[get("/status")]
+fn status(runtime: &State<Arc<PipelineRuntime>>) -> (ContentType, String) {
+    (ContentType::JSON, runtime.supervisor().status().to_string())
+}
+
 #[launch]
 fn rocket() -> _ {
     let config_content = std::fs::read_to_string(
@@ -125,96 +1013,46 @@ fn rocket() -> _ {
             .expect("watson-vision must be called with at least one argument"),
     )
     .expect("the first argument must be a path to a config.json");
-    let (send, recv) = crossbeam_channel::bounded(2);
-    let (data_send, data_recv) = crossbeam_channel::bounded(0);
-    let cfg2 = config_content.clone();
-    *APRILTAG_THREAD_JOINHANDLE.blocking_lock() = Some(std::thread::spawn(move || {
-        let config_content = cfg2;
-        let data_send = data_send;
-        let send: Sender<Vec<u8>> = send;
-        loop {
-            match APRILTAG_THREAD_STOP.lock().map(|x| *x) {
-                Ok(false) => {}
-                _ => break,
-            }
-            let config_content = config_content.clone();
-            if let Err(_e) = std::panic::catch_unwind(|| {
-                let config: config::Config =
-                    serde_json::from_str(&config_content).unwrap();
-                #[cfg(not(target_os = "linux"))]
-                let mut capture = pipeline::capture::TestCapture::default();
-                #[cfg(target_os = "linux")]
-                let mut capture = pipeline::capture::GStreamerCapture::default();
-                let mut fiducial_detector = fiducial_detector::ArucoFiducialDetector::new(
-                    opencv::aruco::DICT_APRILTAG_36h11,
-                );
-                let mut pose_estimator =
-                    pipeline::camera_pose_estimator::MultiTargetCameraPoseEstimator;
-                let mut start = Instant::now();
-                loop {
-                    match APRILTAG_THREAD_STOP.lock().map(|x| *x) {
-                        Ok(false) => {}
-                        _ => break,
-                    }
-                    let next = Instant::now();
-                    let _fps = 1.0 / next.duration_since(start).as_secs_f64();
-                    start = next;
-                    let (retval, mut frame) = capture.get_frame(&config);
-                    if !retval {
-                        std::thread::sleep(Duration::from_millis(100));
-                        continue;
-                    }
-                    let tags = fiducial_detector.detect_fiducial(&mut frame, &config);
-                    if let Some(pose) = pose_estimator.solve_camera_pose(tags, &config) {
-                        let mut io = std::io::Cursor::new(Vec::with_capacity(44));
-                        let (server_time, instant) = NT_TIME.lock().unwrap().to_owned();
-                        let time = Instant::now().duration_since(instant).as_micros() as u32 + server_time;
-                        pose.write_be_args(&mut io, (time,)).unwrap();
-                        _ = data_send.send_timeout(io.into_inner(), Duration::from_millis(4));
-                    }
-
-                    let mut data = VectorOfu8::new();
-                    opencv::imgcodecs::imencode_def(".jpg", &frame, &mut data).unwrap();
-                    let data = (*b"--FRAME\r\nContent-Type: image/jpeg\r\n\r\n")
-                        .into_iter()
-                        .chain(data)
-                        .chain(*b"\r\n")
-                        .collect::<Vec<_>>();
-                    _ = send.send_timeout(data.to_vec(), Duration::from_millis(8));
-                }
-            }) {
-                eprintln!("Error in camera stream");
-            }
-        }
-    }));
-    let config: config::Config = serde_json::from_str(&config_content).unwrap();
+    let config = config::Config::parse(&config_content).unwrap();
+    let runtime = Arc::new(PipelineRuntime::new(config_content).unwrap());
     let figment = rocket::Config::figment()
         .merge(("address", "0.0.0.0"))
         .merge(("port", config.stream_port));
+    let start_runtime = runtime.clone();
+    let stop_runtime = runtime.clone();
     rocket::custom(figment)
-        .manage(recv)
-        .attach(AdHoc::on_liftoff("Startup NetworkTables", |_| {
+        .manage(Streams(runtime.streams()))
+        .manage(runtime)
+        .attach(AdHoc::on_liftoff("Start Pipeline", |rocket| {
+            let shutdown = rocket.shutdown();
             Box::pin(async move {
-                let data_recv = data_recv;
+                // systemd stops the service with SIGTERM
+                #[cfg(unix)]
                 tokio::spawn(async move {
-                    let data_recv = data_recv;
-                    let config_content = config_content.clone();
-                    loop {
-                        if let Err(e) = nt_thread(&data_recv, &config_content).await {
-                            eprintln!("NetworkTables error: {}", e);
-                            tokio::time::sleep(Duration::from_millis(500)).await;
-                        }
+                    use tokio::signal::unix::{signal, SignalKind};
+                    if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+                        terminate.recv().await;
+                        shutdown.notify();
                     }
                 });
+                start_runtime.start().await;
             })
         }))
-        .attach(AdHoc::on_shutdown("Shutdown Apriltag Thread", |_| {
+        .attach(AdHoc::on_shutdown("Stop Pipeline", |_| {
             Box::pin(async move {
-                *APRILTAG_THREAD_STOP.lock().unwrap() = true;
-                if let Some(handle) = APRILTAG_THREAD_JOINHANDLE.lock().await.take() {
-                    handle.join().unwrap();
-                }
+                stop_runtime.stop().await;
             })
         }))
-        .mount("/", routes![index, mjpeg_stream])
+        .mount("/", routes![
+            index,
+            metrics,
+            mjpeg_stream,
+            restart,
+            status,
+            pipeline_status,
+            select_pipeline,
+            snapshot_image,
+            snapshots,
+            snapshot_file
+        ])
 }
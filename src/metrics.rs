@@ -0,0 +1,151 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+/// Prometheus metrics served on `/metrics`. Every metric but `stage_errors` is labeled by `camera_name`.
+pub struct Metrics {
+    registry: Registry,
+    /// Seconds
+    pub frame_time: HistogramVec,
+    /// Labeled by `tag_id` as well
+    pub detections: IntCounterVec,
+    /// Pixels
+    pub pnp_error: HistogramVec,
+    /// Only counts reconnections of an established connection
+    pub nt_reconnects: IntCounterVec,
+    /// Failed attempts to connect, every 500ms while the server is unreachable
+    pub nt_connect_failures: IntCounterVec,
+    /// Errors of an established connection, like publishes that time out, and of a bad configuration
+    pub nt_errors: IntCounterVec,
+    pub mjpeg_clients: IntGaugeVec,
+    pub dropped_stream_frames: IntCounterVec,
+    /// Labeled by `stage`, see [`crate::runtime::Supervisor::report`]
+    pub stage_errors: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("watson_vision".into()), None).unwrap();
+        let frame_time = HistogramVec::new(
+            HistogramOpts::new("frame_time_seconds", "Time to process one camera frame")
+                .buckets(vec![0.005, 0.01, 0.02, 0.033, 0.05, 0.1, 0.25, 0.5, 1.0]),
+            &["camera_name"],
+        )
+        .unwrap();
+        let detections = IntCounterVec::new(
+            Opts::new("detections_total", "Fiducials detected"),
+            &["camera_name", "tag_id"],
+        )
+        .unwrap();
+        let pnp_error = HistogramVec::new(
+            HistogramOpts::new("pnp_error_pixels", "Reprojection error of the best camera pose")
+                .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 25.0]),
+            &["camera_name"],
+        )
+        .unwrap();
+        let nt_reconnects = IntCounterVec::new(
+            Opts::new("nt_reconnects_total", "NetworkTables reconnections"),
+            &["camera_name"],
+        )
+        .unwrap();
+        let nt_connect_failures = IntCounterVec::new(
+            Opts::new(
+                "nt_connect_failures_total",
+                "Failed attempts to connect to NetworkTables",
+            ),
+            &["camera_name"],
+        )
+        .unwrap();
+        let nt_errors = IntCounterVec::new(
+            Opts::new(
+                "nt_errors_total",
+                "NetworkTables errors other than failures to connect",
+            ),
+            &["camera_name"],
+        )
+        .unwrap();
+        let mjpeg_clients = IntGaugeVec::new(
+            Opts::new("mjpeg_clients", "Connected MJPEG stream clients"),
+            &["camera_name"],
+        )
+        .unwrap();
+        let dropped_stream_frames = IntCounterVec::new(
+            Opts::new(
+                "dropped_stream_frames_total",
+                "Encoded frames that could not be handed to the MJPEG stream",
+            ),
+            &["camera_name"],
+        )
+        .unwrap();
+        let stage_errors = IntCounterVec::new(
+            Opts::new("stage_errors_total", "Errors reported by pipeline stages"),
+            &["stage"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(frame_time.clone())).unwrap();
+        registry.register(Box::new(detections.clone())).unwrap();
+        registry.register(Box::new(pnp_error.clone())).unwrap();
+        registry.register(Box::new(nt_reconnects.clone())).unwrap();
+        registry
+            .register(Box::new(nt_connect_failures.clone()))
+            .unwrap();
+        registry.register(Box::new(nt_errors.clone())).unwrap();
+        registry.register(Box::new(mjpeg_clients.clone())).unwrap();
+        registry
+            .register(Box::new(dropped_stream_frames.clone()))
+            .unwrap();
+        registry.register(Box::new(stage_errors.clone())).unwrap();
+
+        Self {
+            registry,
+            frame_time,
+            detections,
+            pnp_error,
+            nt_reconnects,
+            nt_connect_failures,
+            nt_errors,
+            mjpeg_clients,
+            dropped_stream_frames,
+            stage_errors,
+        }
+    }
+
+    /// Metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+/// Counts a connected MJPEG client for as long as it is alive.
+pub struct StreamClient {
+    camera_name: String,
+}
+
+impl StreamClient {
+    pub fn new(camera_name: &str) -> Self {
+        METRICS
+            .mjpeg_clients
+            .with_label_values(&[camera_name])
+            .inc();
+        Self {
+            camera_name: camera_name.to_owned(),
+        }
+    }
+}
+
+impl Drop for StreamClient {
+    fn drop(&mut self) {
+        METRICS
+            .mjpeg_clients
+            .with_label_values(&[&self.camera_name])
+            .dec();
+    }
+}
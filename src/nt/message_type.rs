@@ -10,6 +10,9 @@ pub enum Type {
     String,
     Json,
     Raw,
+    /// Raw data with the type string PhotonLib expects
+    #[serde(rename = "rawBytes")]
+    RawBytes,
     Rpc,
     MsgPack,
     ProtoBuf,
@@ -36,6 +39,7 @@ impl Type {
             Self::String => 4,
             Self::Json => 4,
             Self::Raw => 5,
+            Self::RawBytes => 5,
             Self::Rpc => 5,
             Self::MsgPack => 5,
             Self::ProtoBuf => 5,
@@ -57,6 +61,7 @@ impl Type {
             Self::String => "string",
             Self::Json => "json",
             Self::Raw => "raw",
+            Self::RawBytes => "rawBytes",
             Self::Rpc => "rpc",
             Self::MsgPack => "msgpack",
             Self::ProtoBuf => "protobuf",
@@ -96,6 +101,7 @@ impl Type {
             "string" => Some(Self::String),
             "json" => Some(Self::Json),
             "raw" => Some(Self::Raw),
+            "rawBytes" => Some(Self::RawBytes),
             "rpc" => Some(Self::Rpc),
             "msgpack" => Some(Self::MsgPack),
             "protobuf" => Some(Self::ProtoBuf),
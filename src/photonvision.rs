@@ -0,0 +1,333 @@
+use binrw::BinWrite;
+use nalgebra::Isometry3;
+use opencv::core::MatTraitConst;
+use rmpv::Value;
+
+use crate::{
+    config::CameraConfig,
+    nt::{self, PublishProperties},
+    pipeline::targeting::TagTarget,
+    types::{CameraPoseObservation, FiducialImageObservation},
+};
+
+/// Number of tag IDs in the multi-tag result, unused slots are -1
+const MAX_MULTI_TAG_IDS: usize = 32;
+
+fn write_transform<W: std::io::Write + std::io::Seek>(
+    transform: &Isometry3<f64>,
+    writer: &mut W,
+) -> binrw::BinResult<()> {
+    transform.translation.x.write_be(writer)?;
+    transform.translation.y.write_be(writer)?;
+    transform.translation.z.write_be(writer)?;
+    transform.rotation.w.write_be(writer)?;
+    transform.rotation.vector().x.write_be(writer)?;
+    transform.rotation.vector().y.write_be(writer)?;
+    transform.rotation.vector().z.write_be(writer)?;
+    Ok(())
+}
+
+/// A tag in the PhotonLib 2024 `PhotonTrackedTarget` format.
+#[derive(Debug, Clone)]
+pub struct PhotonTrackedTarget {
+    pub target: TagTarget,
+    pub best_camera_to_target: Isometry3<f64>,
+    pub alt_camera_to_target: Isometry3<f64>,
+    pub pose_ambiguity: f64,
+    pub corners: [[f64; 2]; 4],
+}
+
+impl BinWrite for PhotonTrackedTarget {
+    type Args<'a> = ();
+
+    fn write_options<W: std::io::prelude::Write + std::io::prelude::Seek>(
+        &self,
+        writer: &mut W,
+        _endian: binrw::Endian,
+        _args: Self::Args<'_>,
+    ) -> binrw::prelude::BinResult<()> {
+        self.target.yaw.write_be(writer)?;
+        self.target.pitch.write_be(writer)?;
+        self.target.area.write_be(writer)?;
+        self.target.skew.write_be(writer)?;
+        (self.target.tag_id as i32).write_be(writer)?;
+        write_transform(&self.best_camera_to_target, writer)?;
+        write_transform(&self.alt_camera_to_target, writer)?;
+        self.pose_ambiguity.write_be(writer)?;
+        // The tag itself stands in for the minimum area rectangle
+        for [x, y] in self.corners {
+            x.write_be(writer)?;
+            y.write_be(writer)?;
+        }
+        (self.corners.len() as u8).write_be(writer)?;
+        for [x, y] in self.corners {
+            x.write_be(writer)?;
+            y.write_be(writer)?;
+        }
+        Ok(())
+    }
+}
+
+/// The PhotonLib 2024 `PhotonPipelineResult` format, published to `rawBytes`.
+#[derive(Debug, Clone)]
+pub struct PhotonPipelineResult {
+    pub latency_ms: f64,
+    /// Largest first
+    pub targets: Vec<PhotonTrackedTarget>,
+    /// Field to camera transform and its reprojection error when more than one tag was used
+    pub multi_tag: Option<(Isometry3<f64>, f64)>,
+    /// Tags used for `multi_tag`
+    pub multi_tag_ids: Vec<u64>,
+}
+
+impl PhotonPipelineResult {
+    /// Combines the detections of one frame with the pose solved from them.
+    pub fn new(
+        latency_ms: f64,
+        observations: &[FiducialImageObservation],
+        targets: &[TagTarget],
+        pose: Option<&CameraPoseObservation>,
+        config_store: &CameraConfig,
+    ) -> Self {
+        let mut photon_targets = observations
+            .iter()
+            .zip(targets)
+            .map(|(observation, target)| {
+                let tag_pose = config_store
+                    .tag_layout
+                    .tags
+                    .iter()
+                    .find(|x| x.id == observation.tag_id)
+                    .map(|x| x.pose);
+                let camera_to_target = |field_to_camera: &Isometry3<f64>| {
+                    tag_pose.map_or(Isometry3::identity(), |tag_pose| {
+                        field_to_camera.inverse() * tag_pose
+                    })
+                };
+                let (best, alt, ambiguity) = match pose {
+                    Some(pose) if tag_pose.is_some() && pose.tag_ids.contains(&target.tag_id) => {
+                        let best = camera_to_target(&pose.pose_0);
+                        match (pose.pose_1, pose.error_1) {
+                            (Some(pose_1), Some(error_1)) => (
+                                best,
+                                camera_to_target(&pose_1),
+                                if error_1 > 0.0 {
+                                    pose.error_0 / error_1
+                                } else {
+                                    1.0
+                                },
+                            ),
+                            _ => (best, best, 0.0),
+                        }
+                    }
+                    _ => (Isometry3::identity(), Isometry3::identity(), -1.0),
+                };
+                PhotonTrackedTarget {
+                    target: target.clone(),
+                    best_camera_to_target: best,
+                    alt_camera_to_target: alt,
+                    pose_ambiguity: ambiguity,
+                    corners: observation.corners,
+                }
+            })
+            .collect::<Vec<_>>();
+        photon_targets.sort_by(|a, b| b.target.area.total_cmp(&a.target.area));
+        let multi_tag = pose.filter(|x| x.tag_ids.len() > 1);
+        Self {
+            latency_ms,
+            targets: photon_targets,
+            multi_tag: multi_tag.map(|x| (x.pose_0, x.error_0)),
+            multi_tag_ids: multi_tag.map_or(Vec::new(), |x| x.tag_ids.clone()),
+        }
+    }
+}
+
+impl BinWrite for PhotonPipelineResult {
+    type Args<'a> = ();
+
+    fn write_options<W: std::io::prelude::Write + std::io::prelude::Seek>(
+        &self,
+        writer: &mut W,
+        _endian: binrw::Endian,
+        _args: Self::Args<'_>,
+    ) -> binrw::prelude::BinResult<()> {
+        self.latency_ms.write_be(writer)?;
+
+        if let Some((field_to_camera, error)) = &self.multi_tag {
+            1u8.write_be(writer)?;
+            write_transform(field_to_camera, writer)?;
+            write_transform(field_to_camera, writer)?;
+            error.write_be(writer)?;
+            error.write_be(writer)?;
+            0.0f64.write_be(writer)?;
+        } else {
+            0u8.write_be(writer)?;
+        }
+        for i in 0..MAX_MULTI_TAG_IDS {
+            self.multi_tag_ids
+                .get(i)
+                .map_or(-1i16, |x| *x as i16)
+                .write_be(writer)?;
+        }
+
+        (self.targets.len().min(u8::MAX as usize) as u8).write_be(writer)?;
+        for target in self.targets.iter().take(u8::MAX as usize) {
+            target.write_be(writer)?;
+        }
+        Ok(())
+    }
+}
+
+/// Camera matrix and distortion coefficients of a camera, as published by PhotonVision.
+pub fn calibration(config_store: &CameraConfig) -> opencv::Result<[Vec<f64>; 2]> {
+    Ok([
+        config_store.camera_matrix.data_typed::<f64>()?.to_vec(),
+        config_store
+            .distortion_coefficients
+            .data_typed::<f64>()?
+            .to_vec(),
+    ])
+}
+
+/// Topics under `/photonvision/<camera>/` that PhotonLib's `PhotonCamera` reads.
+pub struct PhotonPublisher {
+    raw_bytes: nt::PublishedTopic,
+    latency_millis: nt::PublishedTopic,
+    has_target: nt::PublishedTopic,
+    target_pitch: nt::PublishedTopic,
+    target_yaw: nt::PublishedTopic,
+    target_area: nt::PublishedTopic,
+    target_skew: nt::PublishedTopic,
+    target_pose: nt::PublishedTopic,
+    target_pixels_x: nt::PublishedTopic,
+    target_pixels_y: nt::PublishedTopic,
+    heartbeat: nt::PublishedTopic,
+    heartbeat_count: i64,
+}
+
+impl PhotonPublisher {
+    /// `version` has to match the PhotonLib version the robot is built with, or `PhotonCamera` refuses to run.
+    /// `calibration` is the camera matrix and distortion coefficients, see [`calibration`].
+    pub async fn new(
+        client: &nt::Client,
+        camera_name: &str,
+        version: &str,
+        calibration: [Vec<f64>; 2],
+    ) -> anyhow::Result<Self> {
+        let publish = |name: &str, r#type: nt::Type, retained: bool| {
+            client.publish_topic(
+                format!("/photonvision/{}/{}", camera_name, name),
+                r#type,
+                Some(PublishProperties {
+                    persistent: Some(false),
+                    retained: Some(retained),
+                    rest: None,
+                }),
+            )
+        };
+
+        for topic in [
+            client
+                .publish_topic(
+                    "/photonvision/version",
+                    nt::Type::String,
+                    Some(PublishProperties {
+                        persistent: Some(false),
+                        retained: Some(true),
+                        rest: None,
+                    }),
+                )
+                .await?,
+            publish("version", nt::Type::String, true).await?,
+        ] {
+            client.publish_value(&topic, &Value::from(version)).await?;
+        }
+        for (name, values) in ["cameraIntrinsics", "cameraDistortion"]
+            .into_iter()
+            .zip(calibration)
+        {
+            let topic = publish(name, nt::Type::DoubleArray, true).await?;
+            client
+                .publish_value(
+                    &topic,
+                    &Value::Array(values.into_iter().map(Value::F64).collect()),
+                )
+                .await?;
+        }
+
+        Ok(Self {
+            raw_bytes: publish("rawBytes", nt::Type::RawBytes, false).await?,
+            latency_millis: publish("latencyMillis", nt::Type::Double, false).await?,
+            has_target: publish("hasTarget", nt::Type::Boolean, false).await?,
+            target_pitch: publish("targetPitch", nt::Type::Double, false).await?,
+            target_yaw: publish("targetYaw", nt::Type::Double, false).await?,
+            target_area: publish("targetArea", nt::Type::Double, false).await?,
+            target_skew: publish("targetSkew", nt::Type::Double, false).await?,
+            target_pose: publish("targetPose", nt::Type::DoubleArray, false).await?,
+            target_pixels_x: publish("targetPixelsX", nt::Type::Double, false).await?,
+            target_pixels_y: publish("targetPixelsY", nt::Type::Double, false).await?,
+            heartbeat: publish("heartbeat", nt::Type::Int, false).await?,
+            heartbeat_count: 0,
+        })
+    }
+
+    pub async fn publish(
+        &mut self,
+        client: &nt::Client,
+        result: &PhotonPipelineResult,
+    ) -> anyhow::Result<()> {
+        let mut io = std::io::Cursor::new(Vec::new());
+        result.write_be(&mut io)?;
+        client
+            .publish_value(&self.raw_bytes, &Value::Binary(io.into_inner()))
+            .await?;
+        client
+            .publish_value(&self.latency_millis, &Value::F64(result.latency_ms))
+            .await?;
+        client
+            .publish_value(
+                &self.has_target,
+                &Value::Boolean(!result.targets.is_empty()),
+            )
+            .await?;
+        if let Some(best) = result.targets.first() {
+            let pose = &best.best_camera_to_target;
+            let [x, y] = best.corners.iter().fold([0.0, 0.0], |sum, corner| {
+                [sum[0] + corner[0] / 4.0, sum[1] + corner[1] / 4.0]
+            });
+            for (topic, value) in [
+                (&self.target_pitch, best.target.pitch),
+                (&self.target_yaw, best.target.yaw),
+                (&self.target_area, best.target.area),
+                (&self.target_skew, best.target.skew),
+                (&self.target_pixels_x, x),
+                (&self.target_pixels_y, y),
+            ] {
+                client.publish_value(topic, &Value::F64(value)).await?;
+            }
+            client
+                .publish_value(
+                    &self.target_pose,
+                    &Value::Array(
+                        [
+                            pose.translation.x,
+                            pose.translation.y,
+                            pose.translation.z,
+                            pose.rotation.w,
+                            pose.rotation.vector().x,
+                            pose.rotation.vector().y,
+                            pose.rotation.vector().z,
+                        ]
+                        .map(Value::F64)
+                        .to_vec(),
+                    ),
+                )
+                .await?;
+        }
+        self.heartbeat_count += 1;
+        client
+            .publish_value(&self.heartbeat, &Value::from(self.heartbeat_count))
+            .await?;
+        Ok(())
+    }
+}
@@ -1,11 +1,16 @@
-use nalgebra::{Isometry3, Translation3, UnitQuaternion};
+use nalgebra::{Isometry3, Matrix6, Translation3, UnitQuaternion, Vector3};
 use opencv::{
     core::Vec2d,
     types::{VectorOfVec2d, VectorOfVec3d, VectorOff64},
 };
 
+use super::{
+    error::PipelineError,
+    reprojection::{refine_pose, reprojection_error, View},
+    robot_heading::{heading_error, solve_heading_constrained, RobotHeading},
+};
 use crate::{
-    config::Config,
+    config::{CameraConfig, StdDevConfig},
     types::{
         isometry_from_opencv, translation_to_opencv, CameraPoseObservation,
         FiducialImageObservation,
@@ -16,26 +21,123 @@ pub trait CameraPoseEstimator {
     fn solve_camera_pose(
         &mut self,
         image_observations: Vec<FiducialImageObservation>,
-        config_store: &Config,
-    ) -> Option<CameraPoseObservation>;
+        config_store: &CameraConfig,
+    ) -> Result<Option<CameraPoseObservation>, PipelineError>;
+}
+
+/// Field relative positions of the four corners of a fiducial, in the order they are detected.
+pub fn fiducial_corners(tag_pose: Isometry3<f64>, fiducial_size: f64) -> [Vector3<f64>; 4] {
+    let half = fiducial_size / 2.0;
+    [
+        Translation3::new(0.0, half, -half),
+        Translation3::new(0.0, -half, -half),
+        Translation3::new(0.0, -half, half),
+        Translation3::new(0.0, half, half),
+    ]
+    .map(|corner| {
+        (tag_pose * Isometry3::from_parts(corner, UnitQuaternion::identity()))
+            .translation
+            .vector
+    })
+}
+
+/// Standard deviations of x, y, z, roll, pitch and yaw of a pose solved from `tag_count` tags that were
+/// `average_distance` meters away on average.
+pub fn measurement_std_devs(
+    config: &StdDevConfig,
+    average_distance: f64,
+    tag_count: usize,
+    error: f64,
+    ambiguity: f64,
+    covariance: Option<&Matrix6<f64>>,
+) -> [f64; 6] {
+    if let (true, Some(covariance)) = (config.from_covariance, covariance) {
+        return std::array::from_fn(|i| covariance[(i, i)].sqrt());
+    }
+    let scale = average_distance.powf(config.distance_exponent)
+        / (tag_count as f64).powf(config.tag_count_exponent)
+        * (1.0 + config.error_scale * error)
+        * (1.0 + config.ambiguity_scale * ambiguity);
+    config.base.map(|x| x * scale)
 }
 
-pub struct MultiTargetCameraPoseEstimator;
+#[derive(Default)]
+pub struct MultiTargetCameraPoseEstimator {
+    /// Used to resolve the ambiguity of single tag solves when the robot is publishing its heading
+    pub robot_heading: Option<RobotHeading>,
+}
+
+/// Corners of a fiducial that is in the tag layout.
+struct TagCorners {
+    tag_id: u64,
+    tag_pose: Isometry3<f64>,
+    object_points: [Vector3<f64>; 4],
+    image_points: [[f64; 2]; 4],
+}
+
+impl TagCorners {
+    fn residual(
+        &self,
+        field_to_camera: &Isometry3<f64>,
+        config_store: &CameraConfig,
+    ) -> Result<f64, PipelineError> {
+        Ok(reprojection_error(
+            field_to_camera,
+            &self.object_points,
+            &self.image_points,
+            &config_store.camera_matrix,
+            &config_store.distortion_coefficients,
+        )?)
+    }
+}
+
+/// Solves for the field relative pose of the camera using every corner of `tags` at once. Returns the pose and its
+/// reprojection error, or `None` if there is no solution.
+fn solve_multi_tag<'a>(
+    tags: impl IntoIterator<Item = &'a TagCorners>,
+    config_store: &CameraConfig,
+) -> opencv::Result<Option<(Isometry3<f64>, f64)>> {
+    let mut object_points = VectorOfVec3d::new();
+    let mut image_points = VectorOfVec2d::new();
+    for tag in tags {
+        object_points.extend(tag.object_points.map(translation_to_opencv));
+        image_points.extend(tag.image_points.map(Vec2d::from_array));
+    }
+    let mut rvecs = VectorOfVec3d::new();
+    let mut tvecs = VectorOfVec3d::new();
+    let mut errors = VectorOff64::new();
+    opencv::calib3d::solve_pnp_generic(
+        &object_points,
+        &image_points,
+        &config_store.camera_matrix,
+        &config_store.distortion_coefficients,
+        &mut rvecs,
+        &mut tvecs,
+        false,
+        opencv::calib3d::SolvePnPMethod::SOLVEPNP_SQPNP,
+        &opencv::core::no_array(),
+        &opencv::core::no_array(),
+        &mut errors,
+    )?;
+    if tvecs.len() < 1 || rvecs.len() < 1 || errors.len() < 1 {
+        return Ok(None);
+    }
+
+    let camera_to_field_pose = isometry_from_opencv(tvecs.get(0)?, rvecs.get(0)?);
+    Ok(Some((camera_to_field_pose.inverse(), errors.get(0)?)))
+}
 
 impl CameraPoseEstimator for MultiTargetCameraPoseEstimator {
     fn solve_camera_pose(
         &mut self,
         image_observations: Vec<FiducialImageObservation>,
-        config_store: &Config,
-    ) -> Option<CameraPoseObservation> {
+        config_store: &CameraConfig,
+    ) -> Result<Option<CameraPoseObservation>, PipelineError> {
         if image_observations.len() == 0 {
-            return None;
+            return Ok(None);
         }
         let fid_size = config_store.fiducial_size_m;
-        let mut object_points = VectorOfVec3d::new();
-        let mut image_points = VectorOfVec2d::new();
-        let mut tag_ids = Vec::new();
-        let mut tag_poses = Vec::new();
+        let mut tags = Vec::new();
         for observation in image_observations {
             if let Some(tag_pose) = config_store
                 .tag_layout
@@ -44,52 +146,67 @@ impl CameraPoseEstimator for MultiTargetCameraPoseEstimator {
                 .find(|x| x.id == observation.tag_id)
                 .map(|x| x.pose)
             {
-                let corner_0 = tag_pose
-                    * Isometry3::<f64>::from_parts(
-                        Translation3::new(0.0, fid_size / 2.0, -fid_size / 2.0),
-                        UnitQuaternion::identity(),
-                    );
-                let corner_1 = tag_pose
-                    * Isometry3::<f64>::from_parts(
-                        Translation3::new(0.0, -fid_size / 2.0, -fid_size / 2.0),
-                        UnitQuaternion::identity(),
-                    );
-                let corner_2 = tag_pose
-                    * Isometry3::<f64>::from_parts(
-                        Translation3::new(0.0, -fid_size / 2.0, fid_size / 2.0),
-                        UnitQuaternion::identity(),
-                    );
-                let corner_3 = tag_pose
-                    * Isometry3::<f64>::from_parts(
-                        Translation3::new(0.0, fid_size / 2.0, fid_size / 2.0),
-                        UnitQuaternion::identity(),
-                    );
-                object_points.extend([
-                    translation_to_opencv(corner_0.translation.vector),
-                    translation_to_opencv(corner_1.translation.vector),
-                    translation_to_opencv(corner_2.translation.vector),
-                    translation_to_opencv(corner_3.translation.vector),
-                ]);
-
-                image_points.extend([
-                    Vec2d::from_array(observation.corners[0]),
-                    Vec2d::from_array(observation.corners[1]),
-                    Vec2d::from_array(observation.corners[2]),
-                    Vec2d::from_array(observation.corners[3]),
-                ]);
-
-                tag_ids.push(observation.tag_id);
-                tag_poses.push(tag_pose);
+                tags.push(TagCorners {
+                    tag_id: observation.tag_id,
+                    tag_pose,
+                    object_points: fiducial_corners(tag_pose, fid_size),
+                    image_points: observation.corners,
+                });
             }
         }
 
-        if tag_ids.len() == 0 {
-            return None;
-        } else if tag_ids.len() == 1 {
+        if tags.len() == 0 {
+            return Ok(None);
+        } else if tags.len() == 1 {
+            let heading = self.robot_heading.as_ref().and_then(|x| x.get());
+            if let (Some(heading), true) = (heading, config_store.heading_constrained_solve) {
+                let tag = &tags[0];
+                let Some(field_to_camera) = solve_heading_constrained(
+                    &tag.object_points,
+                    &tag.image_points,
+                    heading,
+                    config_store,
+                )?
+                else {
+                    return Ok(None);
+                };
+                let error = tag.residual(&field_to_camera, config_store)?;
+                return Ok(Some(CameraPoseObservation {
+                    tag_ids: vec![tag.tag_id],
+                    pose_0: field_to_camera,
+                    error_0: error,
+                    pose_1: None,
+                    error_1: None,
+                    tag_residuals: vec![error],
+                    rejected_tag_ids: Vec::new(),
+                    covariance: None,
+                    rejection: None,
+                    std_devs: measurement_std_devs(
+                        &config_store.std_devs,
+                        (field_to_camera.translation.vector - tag.tag_pose.translation.vector)
+                            .norm(),
+                        1,
+                        error,
+                        0.0,
+                        None,
+                    ),
+                }));
+            }
+
+            let object_points = tags[0]
+                .object_points
+                .map(translation_to_opencv)
+                .into_iter()
+                .collect::<VectorOfVec3d>();
+            let image_points = tags[0]
+                .image_points
+                .map(Vec2d::from_array)
+                .into_iter()
+                .collect::<VectorOfVec2d>();
             let mut rvecs = VectorOfVec3d::new();
             let mut tvecs = VectorOfVec3d::new();
             let mut errors = VectorOff64::new();
-            if let Err(e) = opencv::calib3d::solve_pnp_generic(
+            opencv::calib3d::solve_pnp_generic(
                 &object_points,
                 &image_points,
                 &config_store.camera_matrix,
@@ -101,60 +218,145 @@ impl CameraPoseEstimator for MultiTargetCameraPoseEstimator {
                 &opencv::core::no_array(),
                 &opencv::core::no_array(),
                 &mut errors,
-            ) {
-                eprintln!("{}", e);
-                return None;
+            )?;
+            let field_to_tag_pose = tags[0].tag_pose;
+            if tvecs.len() < 2 || rvecs.len() < 2 || errors.len() < 2 {
+                return Ok(None);
             }
-            let field_to_tag_pose = tag_poses[0];
-            if tvecs.len() < 2 || rvecs.len() < 2 {
-                println!("Invalid tvecs/rvecs");
-                return None;
+            let camera_to_tag_pose_0 = isometry_from_opencv(tvecs.get(0)?, rvecs.get(0)?);
+            let camera_to_tag_pose_1 = isometry_from_opencv(tvecs.get(1)?, rvecs.get(1)?);
+            let mut field_to_camera_0 = field_to_tag_pose * camera_to_tag_pose_0.inverse();
+            let mut field_to_camera_1 = field_to_tag_pose * camera_to_tag_pose_1.inverse();
+            let mut error_0 = errors.get(0)?;
+            let mut error_1 = errors.get(1)?;
+            // Prefer the candidate facing the way the robot says it is
+            if let Some(heading) = heading {
+                if heading_error(&field_to_camera_1, heading, config_store)
+                    < heading_error(&field_to_camera_0, heading, config_store)
+                {
+                    std::mem::swap(&mut field_to_camera_0, &mut field_to_camera_1);
+                    std::mem::swap(&mut error_0, &mut error_1);
+                }
             }
-            let camera_to_tag_pose_0 =
-                isometry_from_opencv(tvecs.get(0).unwrap(), rvecs.get(0).unwrap());
-            let camera_to_tag_pose_1 =
-                isometry_from_opencv(tvecs.get(1).unwrap(), rvecs.get(1).unwrap());
-            let field_to_camera_0 = field_to_tag_pose * camera_to_tag_pose_0.inverse();
-            let field_to_camera_1 = field_to_tag_pose * camera_to_tag_pose_1.inverse();
 
-            return Some(CameraPoseObservation {
-                tag_ids,
+            return Ok(Some(CameraPoseObservation {
+                tag_ids: vec![tags[0].tag_id],
                 pose_0: field_to_camera_0,
-                error_0: errors.get(0).unwrap(),
+                error_0,
                 pose_1: Some(field_to_camera_1),
-                error_1: Some(errors.get(1).unwrap()),
-            });
+                error_1: Some(error_1),
+                tag_residuals: vec![error_0],
+                rejected_tag_ids: Vec::new(),
+                covariance: None,
+                rejection: None,
+                std_devs: measurement_std_devs(
+                    &config_store.std_devs,
+                    camera_to_tag_pose_0.translation.vector.norm(),
+                    1,
+                    error_0,
+                    if error_1 > 0.0 {
+                        error_0 / error_1
+                    } else {
+                        1.0
+                    },
+                    None,
+                ),
+            }));
         } else {
-            let mut rvecs = VectorOfVec3d::new();
-            let mut tvecs = VectorOfVec3d::new();
-            let mut errors = VectorOff64::new();
-            if let Err(e) = opencv::calib3d::solve_pnp_generic(
-                &object_points,
-                &image_points,
-                &config_store.camera_matrix,
-                &config_store.distortion_coefficients,
-                &mut rvecs,
-                &mut tvecs,
-                false,
-                opencv::calib3d::SolvePnPMethod::SOLVEPNP_SQPNP,
-                &opencv::core::no_array(),
-                &opencv::core::no_array(),
-                &mut errors,
-            ) {
-                eprintln!("{}", e);
-                return None;
+            let Some((mut field_to_camera, mut error)) = solve_multi_tag(&tags, config_store)?
+            else {
+                return Ok(None);
+            };
+            let mut rejected_tag_ids = Vec::new();
+            if let Some(outlier_rejection) = &config_store.outlier_rejection {
+                while tags.len() > outlier_rejection.min_tags.max(2) {
+                    let residuals = tags
+                        .iter()
+                        .map(|x| x.residual(&field_to_camera, config_store))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if !residuals
+                        .iter()
+                        .any(|x| *x > outlier_rejection.max_reprojection_error)
+                    {
+                        break;
+                    }
+                    // Drop whichever outlier the rest agree on best without
+                    let mut best: Option<(usize, Isometry3<f64>, f64)> = None;
+                    for skipped in 0..tags.len() {
+                        if residuals[skipped] <= outlier_rejection.max_reprojection_error {
+                            continue;
+                        }
+                        let remaining = tags
+                            .iter()
+                            .enumerate()
+                            .filter(|(i, _)| *i != skipped)
+                            .map(|(_, x)| x);
+                        if let Some((pose, error)) = solve_multi_tag(remaining, config_store)? {
+                            if best.map_or(true, |(_, _, best_error)| error < best_error) {
+                                best = Some((skipped, pose, error));
+                            }
+                        }
+                    }
+                    let Some((skipped, pose, best_error)) = best else {
+                        break;
+                    };
+                    // The tag was not the problem if the rest fit no better without it
+                    if best_error >= error {
+                        break;
+                    }
+                    rejected_tag_ids.push(tags.remove(skipped).tag_id);
+                    field_to_camera = pose;
+                    error = best_error;
+                }
+            }
+
+            let mut covariance = None;
+            if let Some(refinement) = &config_store.refinement {
+                let view = View {
+                    camera_matrix: &config_store.camera_matrix,
+                    distortion_coefficients: &config_store.distortion_coefficients,
+                    body_to_camera: Isometry3::identity(),
+                    object_points: tags.iter().flat_map(|x| x.object_points).collect(),
+                    image_points: tags.iter().flat_map(|x| x.image_points).collect(),
+                };
+                if let Some(refined) =
+                    refine_pose(field_to_camera, &[view], refinement.max_iterations)?
+                {
+                    field_to_camera = refined.pose;
+                    error = refined.error;
+                    covariance = Some(refined.covariance);
+                }
             }
 
-            let camera_to_field_pose =
-                isometry_from_opencv(tvecs.get(0).unwrap(), rvecs.get(0).unwrap());
-            let field_to_camera = camera_to_field_pose.inverse();
-            return Some(CameraPoseObservation {
-                tag_ids,
+            let average_distance = tags
+                .iter()
+                .map(|x| {
+                    (x.tag_pose.translation.vector - field_to_camera.translation.vector).norm()
+                })
+                .sum::<f64>()
+                / tags.len() as f64;
+            return Ok(Some(CameraPoseObservation {
+                tag_ids: tags.iter().map(|x| x.tag_id).collect(),
                 pose_0: field_to_camera,
-                error_0: errors.get(0).unwrap(),
+                error_0: error,
                 pose_1: None,
                 error_1: None,
-            });
+                tag_residuals: tags
+                    .iter()
+                    .map(|x| x.residual(&field_to_camera, config_store))
+                    .collect::<Result<_, _>>()?,
+                rejected_tag_ids,
+                std_devs: measurement_std_devs(
+                    &config_store.std_devs,
+                    average_distance,
+                    tags.len(),
+                    error,
+                    0.0,
+                    covariance.as_ref(),
+                ),
+                covariance,
+                rejection: None,
+            }));
         }
     }
 }
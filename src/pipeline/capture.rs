@@ -1,11 +1,25 @@
-use opencv::{types::VectorOfu8, videoio::VideoCaptureTrait};
+use opencv::{
+    core::MatTraitConst,
+    types::VectorOfu8,
+    videoio::{VideoCaptureTrait, VideoCaptureTraitConst},
+};
 
-use crate::config::Config;
+use super::error::PipelineError;
+use crate::config::CameraConfig;
 
-pub trait Capture: Default {
-    fn get_frame(&mut self, config_store: &Config) -> (bool, opencv::prelude::Mat);
+pub trait Capture {
+    fn get_frame(
+        &mut self,
+        config_store: &CameraConfig,
+    ) -> Result<opencv::prelude::Mat, PipelineError>;
 
-    fn config_changed(config_a: Option<&Config>, config_b: Option<&Config>) -> bool {
+    /// Closes the capture session so the next [`Self::get_frame`] opens a new one
+    fn reopen(&mut self) {}
+
+    fn config_changed(config_a: Option<&CameraConfig>, config_b: Option<&CameraConfig>) -> bool
+    where
+        Self: Sized,
+    {
         if let Some(config_a) = config_a {
             if let Some(config_b) = config_b {
                 return config_a.video_path != config_b.video_path
@@ -27,93 +41,170 @@ pub trait Capture: Default {
 
 #[derive(Debug, Default)]
 pub struct DefaultCapture {
+    /// V4L device index
+    device: i32,
     video: Option<opencv::videoio::VideoCapture>,
-    last_config: Option<Config>,
+    last_config: Option<CameraConfig>,
+}
+
+impl DefaultCapture {
+    pub fn new(device: i32) -> Self {
+        Self {
+            device,
+            ..Default::default()
+        }
+    }
 }
 
 impl Capture for DefaultCapture {
-    fn get_frame(&mut self, config_store: &Config) -> (bool, opencv::prelude::Mat) {
+    fn get_frame(
+        &mut self,
+        config_store: &CameraConfig,
+    ) -> Result<opencv::prelude::Mat, PipelineError> {
         if Self::config_changed(self.last_config.as_ref(), Some(&config_store)) {
             if let Some(mut video) = self.video.take() {
-                video.release().unwrap();
+                video.release()?;
             }
         }
         if let None = self.video {
             let mut video =
-                opencv::videoio::VideoCapture::new(0, opencv::videoio::CAP_V4L).unwrap();
+                opencv::videoio::VideoCapture::new(self.device, opencv::videoio::CAP_V4L)?;
+            if !video.is_opened()? {
+                return Err(PipelineError::Disconnected(format!(
+                    "failed to open video device {}",
+                    self.device
+                )));
+            }
             video
                 .set(
                     opencv::videoio::CAP_PROP_FRAME_WIDTH,
                     config_store.width as f64,
-                )
-                .unwrap();
+                )?;
             video
                 .set(
                     opencv::videoio::CAP_PROP_FRAME_HEIGHT,
                     config_store.height as f64,
-                )
-                .unwrap();
+                )?;
             video
                 .set(
                     opencv::videoio::CAP_PROP_AUTO_EXPOSURE,
                     config_store.auto_exposure as f64,
-                )
-                .unwrap();
+                )?;
             video
                 .set(
                     opencv::videoio::CAP_PROP_EXPOSURE,
                     config_store.exposure as f64,
-                )
-                .unwrap();
+                )?;
             video
-                .set(opencv::videoio::CAP_PROP_GAIN, config_store.gain as f64)
-                .unwrap();
+                .set(opencv::videoio::CAP_PROP_GAIN, config_store.gain as f64)?;
             self.video = Some(video);
         }
         self.last_config = Some(config_store.clone());
 
         let mut mat = opencv::prelude::Mat::default();
-        let ret = self.video.as_mut().unwrap().read(&mut mat).unwrap();
-        (ret, mat)
+        if !self.video.as_mut().unwrap().read(&mut mat)? {
+            return Err(PipelineError::DroppedFrame);
+        }
+        Ok(mat)
+    }
+
+    fn reopen(&mut self) {
+        if let Some(mut video) = self.video.take() {
+            _ = video.release();
+        }
     }
 }
 
+/// Sets the exposure and gain of the V4L2 device at `video_path` with `v4l2-ctl`.
+fn set_controls(config_store: &CameraConfig) -> Result<(), String> {
+    let output = std::process::Command::new("v4l2-ctl")
+        .arg("-d")
+        .arg(&config_store.video_path)
+        .arg("-c")
+        .arg(format!(
+            "exposure_auto={},exposure_absolute={},gain={}",
+            config_store.auto_exposure, config_store.exposure, config_store.gain
+        ))
+        .output()
+        .map_err(|e| format!("can't run v4l2-ctl: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(())
+}
+
 #[derive(Debug, Default)]
 pub struct GStreamerCapture {
     video: Option<opencv::videoio::VideoCapture>,
-    last_config: Option<Config>,
+    last_config: Option<CameraConfig>,
 }
 
 impl Capture for GStreamerCapture {
-    fn get_frame(&mut self, config_store: &Config) -> (bool, opencv::prelude::Mat) {
-        if Self::config_changed(self.last_config.as_ref(), Some(&config_store)) {
+    fn get_frame(
+        &mut self,
+        config_store: &CameraConfig,
+    ) -> Result<opencv::prelude::Mat, PipelineError> {
+        // Only the device and format need a new session, the controls are changed on the running one
+        let session_changed = self.last_config.as_ref().map_or(true, |last_config| {
+            last_config.video_path != config_store.video_path
+                || last_config.width != config_store.width
+                || last_config.height != config_store.height
+        });
+        if session_changed {
             if let Some(mut video) = self.video.take() {
-                video.release().unwrap();
+                video.release()?;
                 std::thread::sleep(std::time::Duration::from_secs(2));
             }
+        } else if Self::config_changed(self.last_config.as_ref(), Some(config_store)) {
+            // OpenCV can't change the controls of a GStreamer session, so they are set on the device. A new session
+            // picks them up through extra_controls if that doesn't work.
+            if self.video.is_some() {
+                if let Err(e) = set_controls(config_store) {
+                    self.reopen();
+                    return Err(PipelineError::Disconnected(format!(
+                        "can't set the controls of {}, restarting the capture session: {}",
+                        config_store.video_path, e
+                    )));
+                }
+            }
         }
         if let None = self.video {
             if config_store.video_path == "" {
-                println!("No camera ID, waiting to start capture session.");
-            } else {
-                println!("Starting capture session");
-                self.video = Some(opencv::videoio::VideoCapture::from_file(&format!("v4l2src device={} extra_controls=\"c,exposure_auto={},exposure_absolute={},gain={},sharpness=0,brightness=0\" ! image/jpeg,format=MJPG,width={},height={} ! jpegdec ! video/x-raw ! appsink drop=1", config_store.video_path, config_store.auto_exposure, config_store.exposure, config_store.gain, config_store.width, config_store.height), opencv::videoio::CAP_GSTREAMER).unwrap());
-                println!("Capture session ready");
+                return Err(PipelineError::Config(format!(
+                    "no video_path for {}",
+                    config_store.camera_name
+                )));
             }
+            println!("Starting capture session");
+            let video = opencv::videoio::VideoCapture::from_file(&format!("v4l2src device={} extra_controls=\"c,exposure_auto={},exposure_absolute={},gain={},sharpness=0,brightness=0\" ! image/jpeg,format=MJPG,width={},height={} ! jpegdec ! video/x-raw ! appsink drop=1", config_store.video_path, config_store.auto_exposure, config_store.exposure, config_store.gain, config_store.width, config_store.height), opencv::videoio::CAP_GSTREAMER)?;
+            if !video.is_opened()? {
+                return Err(PipelineError::Disconnected(format!(
+                    "failed to open {}",
+                    config_store.video_path
+                )));
+            }
+            self.video = Some(video);
+            println!("Capture session ready");
         }
 
         self.last_config = Some(config_store.clone());
 
         let mut image = opencv::prelude::Mat::default();
-        if let Some(video) = self.video.as_mut() {
-            let retval = video.read(&mut image).unwrap();
-            if !retval {
-                video.release().expect("Capture session failed, restarting");
-                panic!("Capture session failed, restarting");
-            }
-            (retval, image)
-        } else {
-            (false, image)
+        let video = self.video.as_mut().unwrap();
+        // appsink drops frames instead of failing, so a failed read means the session is gone
+        if !video.read(&mut image)? {
+            self.reopen();
+            return Err(PipelineError::Disconnected(format!(
+                "capture session of {} failed",
+                config_store.video_path
+            )));
+        }
+        Ok(image)
+    }
+
+    fn reopen(&mut self) {
+        if let Some(mut video) = self.video.take() {
+            _ = video.release();
         }
     }
 }
@@ -131,9 +222,23 @@ impl Default for TestCapture {
     }
 }
 
+impl TestCapture {
+    /// Returns the image at `path` for every frame instead of the built in test image
+    pub fn from_file(path: &str) -> Result<Self, PipelineError> {
+        let test_image = opencv::imgcodecs::imread(path, opencv::imgcodecs::IMREAD_COLOR)?;
+        if test_image.empty() {
+            return Err(PipelineError::Config(format!("failed to read {}", path)));
+        }
+        Ok(Self { test_image })
+    }
+}
+
 impl Capture for TestCapture {
-    fn get_frame(&mut self, _config_store: &Config) -> (bool, opencv::prelude::Mat) {
-        (true, self.test_image.clone())
+    fn get_frame(
+        &mut self,
+        _config_store: &CameraConfig,
+    ) -> Result<opencv::prelude::Mat, PipelineError> {
+        Ok(self.test_image.clone())
     }
 }
 
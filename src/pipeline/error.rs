@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use thiserror::Error;
+
+/// Why a pipeline stage failed to process a frame.
+#[derive(Debug, Error)]
+pub enum PipelineError {
+    /// The capture didn't deliver a frame this time. The next one is likely to work.
+    #[error("dropped frame")]
+    DroppedFrame,
+    /// The capture session is gone, for example because the camera was unplugged.
+    #[error("camera disconnected: {0}")]
+    Disconnected(String),
+    /// The configuration can't work. Retrying won't help until it is changed.
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    /// A result of the frame is inconsistent and can't be published. Only that frame is affected.
+    #[error("can't encode {0}")]
+    Encode(String),
+    /// The stage panicked. It is restarted from scratch.
+    #[error("panicked: {0}")]
+    Panic(String),
+    #[error("OpenCV error: {0}")]
+    OpenCv(#[from] opencv::Error),
+}
+
+impl PipelineError {
+    /// Whether the stage can keep running after this error
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, Self::Config(_))
+    }
+}
+
+/// What a stage should do after an error, decided by the [`crate::runtime::Supervisor`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    /// Close the capture session, wait, then open it again.
+    Reopen(Duration),
+    /// Wait before trying the next frame.
+    Backoff(Duration),
+    /// Give up until the pipeline is restarted.
+    Stop,
+}
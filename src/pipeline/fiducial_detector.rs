@@ -1,17 +1,20 @@
 use opencv::{
     aruco::Dictionary,
-    core::Ptr,
+    core::{Mat, Ptr, CV_8UC1, CV_8UC3},
+    imgproc,
+    prelude::*,
     types::{VectorOfVectorOfPoint2f, VectorOfi32},
 };
 
-use crate::{config::Config, types::FiducialImageObservation};
+use super::error::PipelineError;
+use crate::{config::CameraConfig, types::FiducialImageObservation};
 
 pub trait FiducialDetector {
     fn detect_fiducial(
         &mut self,
-        image: &mut opencv::prelude::Mat,
-        config_store: &Config,
-    ) -> Vec<FiducialImageObservation>;
+        image: &opencv::prelude::Mat,
+        config_store: &CameraConfig,
+    ) -> Result<Vec<FiducialImageObservation>, PipelineError>;
 }
 
 pub struct ArucoFiducialDetector {
@@ -19,39 +22,72 @@ pub struct ArucoFiducialDetector {
 }
 
 impl ArucoFiducialDetector {
-    pub fn new(dictionary_id: i32) -> Self {
-        let aruco_dict = opencv::aruco::get_predefined_dictionary_i32(dictionary_id).unwrap();
-        Self {
+    pub fn new(dictionary_id: i32) -> opencv::Result<Self> {
+        let aruco_dict = opencv::aruco::get_predefined_dictionary_i32(dictionary_id)?;
+        Ok(Self {
             aruco_dict
+        })
+    }
+}
+
+/// Mean difference in brightness, from 0 to 1, between just outside and just inside the edges of a tag. Tags have a
+/// black border on a white background, so blurry or badly lit tags score low.
+fn edge_contrast(gray: &Mat, corners: &[[f64; 2]; 4]) -> opencv::Result<f64> {
+    const SAMPLES: usize = 8;
+    let center = corners.iter().fold([0.0, 0.0], |a, b| [a[0] + b[0] / 4.0, a[1] + b[1] / 4.0]);
+    let pixel = |x: f64, y: f64| -> opencv::Result<f64> {
+        let x = (x.round() as i32).clamp(0, gray.cols() - 1);
+        let y = (y.round() as i32).clamp(0, gray.rows() - 1);
+        Ok(*gray.at_2d::<u8>(y, x)? as f64)
+    };
+    let mut contrast = 0.0;
+    for i in 0..4 {
+        let [a, b] = [corners[i], corners[(i + 1) % 4]];
+        for sample in 0..SAMPLES {
+            let t = (sample as f64 + 0.5) / SAMPLES as f64;
+            let [x, y] = [a[0] + t * (b[0] - a[0]), a[1] + t * (b[1] - a[1])];
+            // A tenth of the way to the center stays within the border, which is a sixth of the tag
+            let [dx, dy] = [(center[0] - x) * 0.1, (center[1] - y) * 0.1];
+            contrast += pixel(x - dx, y - dy)? - pixel(x + dx, y + dy)?;
         }
     }
+    Ok((contrast / (4 * SAMPLES) as f64 / 255.0).max(0.0))
 }
 
 impl FiducialDetector for ArucoFiducialDetector {
     fn detect_fiducial(
         &mut self,
-        image: &mut opencv::prelude::Mat,
-        _config_store: &Config,
-    ) -> Vec<FiducialImageObservation> {
+        image: &opencv::prelude::Mat,
+        _config_store: &CameraConfig,
+    ) -> Result<Vec<FiducialImageObservation>, PipelineError> {
         let mut corners = VectorOfVectorOfPoint2f::default();
         let mut ids = VectorOfi32::default();
-        opencv::aruco::detect_markers_def(image, &self.aruco_dict, &mut corners, &mut ids)
-            .unwrap();
-        opencv::aruco::draw_detected_markers(image, &corners, &ids, opencv::core::Scalar::new(0.0, 255.0, 0.0, 255.0)).unwrap();
-        ids.into_iter().zip(corners).map(|(id, corners)| {
-            let corner1 = corners.get(0).unwrap();
-            let corner2 = corners.get(1).unwrap();
-            let corner3 = corners.get(2).unwrap();
-            let corner4 = corners.get(3).unwrap();
-            FiducialImageObservation {
-                tag_id: id as u64,
-                corners: [
-                    [corner1.x as f64, corner1.y as f64],
-                    [corner2.x as f64, corner2.y as f64],
-                    [corner3.x as f64, corner3.y as f64],
-                    [corner4.x as f64, corner4.y as f64],
-                ]
+        opencv::aruco::detect_markers_def(image, &self.aruco_dict, &mut corners, &mut ids)?;
+        let mut converted = Mat::default();
+        let gray = match image.typ() {
+            CV_8UC1 => Some(image),
+            CV_8UC3 if !ids.is_empty() => {
+                imgproc::cvt_color(image, &mut converted, imgproc::COLOR_BGR2GRAY, 0)?;
+                Some(&converted)
             }
+            _ => None,
+        };
+        ids.into_iter().zip(corners).map(|(id, corners)| -> Result<_, PipelineError> {
+            let corner1 = corners.get(0)?;
+            let corner2 = corners.get(1)?;
+            let corner3 = corners.get(2)?;
+            let corner4 = corners.get(3)?;
+            let corners = [
+                [corner1.x as f64, corner1.y as f64],
+                [corner2.x as f64, corner2.y as f64],
+                [corner3.x as f64, corner3.y as f64],
+                [corner4.x as f64, corner4.y as f64],
+            ];
+            Ok(FiducialImageObservation {
+                tag_id: id as u64,
+                corners,
+                quality: gray.map(|gray| edge_contrast(gray, &corners)).transpose()?,
+            })
         }).collect()
     }
 }
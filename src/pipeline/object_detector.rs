@@ -0,0 +1,735 @@
+use nalgebra::Isometry3;
+use opencv::{
+    core::{Mat, Point, Rect, Scalar, Size, CV_32F},
+    dnn, imgproc,
+    prelude::*,
+    types::{VectorOfMat, VectorOfRect, VectorOfVectorOfPoint, VectorOff32, VectorOfi32},
+};
+use serde::Deserialize;
+
+use super::{
+    error::PipelineError,
+    targeting::{ground_point, normalize_pixels, yaw_pitch},
+};
+use crate::config::CameraConfig;
+
+/// Something other than a fiducial found in the image, like a game piece.
+#[derive(Debug, Clone)]
+pub struct ObjectTarget {
+    /// What kind of object it is, 0 for detectors that only find one kind
+    pub class_id: u32,
+    /// Name of the class, for detectors configured with class names
+    pub class_name: Option<String>,
+    /// Between 0 and 1, `None` for detectors that don't score their detections
+    pub confidence: Option<f64>,
+    /// Degrees from the optical axis to the center of the bounding box, positive right
+    pub yaw: f64,
+    /// Degrees from the optical axis to the center of the bounding box, positive up
+    pub pitch: f64,
+    /// Percent of the image covered by the object
+    pub area: f64,
+    /// Left, top, width and height in pixels
+    pub bounding_box: [f64; 4],
+    /// Robot relative x and y in meters of the bottom center of the bounding box projected onto the floor, see
+    /// [`CameraConfig::target_height_m`]. Only known with a `robot_to_camera`.
+    pub position: Option<[f64; 2]>,
+    /// Top left, top right, bottom right and bottom left in pixels, for detectors that find them
+    pub corners: Option<[[f64; 2]; 4]>,
+    /// Pose of the target relative to the camera, for detectors that know the shape of the target
+    pub camera_to_target: Option<Isometry3<f64>>,
+}
+
+impl ObjectTarget {
+    /// Layout of the double arrays published to NetworkTables
+    pub fn to_array(&self) -> [f64; 11] {
+        let [x, y, width, height] = self.bounding_box;
+        let [position_x, position_y] = self.position.unwrap_or([f64::NAN; 2]);
+        [
+            self.class_id as f64,
+            self.confidence.unwrap_or(f64::NAN),
+            self.yaw,
+            self.pitch,
+            self.area,
+            x,
+            y,
+            width,
+            height,
+            position_x,
+            position_y,
+        ]
+    }
+
+    /// x and y of each of the `corners`, NaN if unknown
+    pub fn corners_array(&self) -> [f64; 8] {
+        let [a, b, c, d] = self.corners.unwrap_or([[f64::NAN; 2]; 4]);
+        [a[0], a[1], b[0], b[1], c[0], c[1], d[0], d[1]]
+    }
+
+    /// Meters and radians, x, y, z, roll, pitch and yaw of `camera_to_target`, NaN if unknown
+    pub fn pose_array(&self) -> [f64; 6] {
+        match self.camera_to_target {
+            Some(pose) => {
+                let (roll, pitch, yaw) = pose.rotation.euler_angles();
+                let translation = pose.translation.vector;
+                [
+                    translation.x,
+                    translation.y,
+                    translation.z,
+                    roll,
+                    pitch,
+                    yaw,
+                ]
+            }
+            None => [f64::NAN; 6],
+        }
+    }
+}
+
+pub trait ObjectDetector {
+    /// Largest first
+    fn detect_objects(
+        &mut self,
+        image: &Mat,
+        config_store: &CameraConfig,
+    ) -> Result<Vec<ObjectTarget>, PipelineError>;
+
+    /// Streamed instead of the camera image when set, for tuning
+    fn debug_image(&self) -> Option<&Mat> {
+        None
+    }
+}
+
+/// Outlines `objects` on the stream image.
+pub fn draw_objects(image: &mut Mat, objects: &[ObjectTarget]) -> opencv::Result<()> {
+    for object in objects {
+        let [x, y, width, height] = object.bounding_box.map(|x| x as i32);
+        imgproc::rectangle(
+            image,
+            Rect::new(x, y, width, height),
+            Scalar::new(255.0, 0.0, 255.0, 0.0),
+            2,
+            imgproc::LINE_8,
+            0,
+        )?;
+    }
+    Ok(())
+}
+
+/// Turns blobs found by a detector into targets, biggest first and at most `max_targets` of them. The sort is stable,
+/// so blobs that are already sorted by area keep their order.
+pub fn object_targets(
+    mut blobs: Vec<(Rect, f64, u32, Option<f64>)>,
+    max_targets: usize,
+    config_store: &CameraConfig,
+) -> opencv::Result<Vec<ObjectTarget>> {
+    blobs.sort_by(|a, b| b.1.total_cmp(&a.1));
+    blobs.truncate(max_targets);
+    let centers = normalize_pixels(
+        blobs.iter().map(|(rect, ..)| {
+            [
+                rect.x as f64 + rect.width as f64 / 2.0,
+                rect.y as f64 + rect.height as f64 / 2.0,
+            ]
+        }),
+        config_store,
+    )?;
+    let bottoms = normalize_pixels(
+        blobs.iter().map(|(rect, ..)| {
+            [
+                rect.x as f64 + rect.width as f64 / 2.0,
+                (rect.y + rect.height) as f64,
+            ]
+        }),
+        config_store,
+    )?;
+    let image_area = config_store.width as f64 * config_store.height as f64;
+    Ok(blobs
+        .into_iter()
+        .zip(centers)
+        .zip(bottoms)
+        .map(|(((rect, area, class_id, confidence), center), bottom)| {
+            let (yaw, pitch) = yaw_pitch(center);
+            ObjectTarget {
+                class_id,
+                class_name: None,
+                confidence,
+                yaw,
+                pitch,
+                area: area / image_area * 100.0,
+                bounding_box: [
+                    rect.x as f64,
+                    rect.y as f64,
+                    rect.width as f64,
+                    rect.height as f64,
+                ],
+                corners: None,
+                camera_to_target: None,
+                position: config_store
+                    .robot_to_camera
+                    .as_ref()
+                    .and_then(|robot_to_camera| {
+                        ground_point(bottom, robot_to_camera, config_store.target_height_m)
+                    }),
+            }
+        })
+        .collect())
+}
+
+/// Options of [`HsvObjectDetector`]. The defaults find the orange 2024 note.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HsvOptions {
+    /// Hue range in OpenCV units, 0 to 180. A minimum above the maximum wraps around through red.
+    pub hue: [f64; 2],
+    /// 0 to 255
+    pub saturation: [f64; 2],
+    /// 0 to 255
+    pub value: [f64; 2],
+    /// Pixels, the size of the elliptical kernel used for morphology
+    pub kernel_size: i32,
+    /// Iterations of opening, which removes specks
+    pub open_iterations: i32,
+    /// Iterations of closing, which fills holes
+    pub close_iterations: i32,
+    /// Percent of the image
+    pub min_area: f64,
+    pub max_area: f64,
+    /// Width over height of the bounding box
+    pub min_aspect_ratio: f64,
+    pub max_aspect_ratio: f64,
+    /// Contour area over bounding box area
+    pub min_fill: f64,
+    pub max_fill: f64,
+    pub max_targets: usize,
+    pub class_id: u32,
+    /// Stream the thresholded mask instead of the camera image
+    pub stream_mask: bool,
+}
+
+impl Default for HsvOptions {
+    fn default() -> Self {
+        Self {
+            hue: [2.0, 22.0],
+            saturation: [120.0, 255.0],
+            value: [120.0, 255.0],
+            kernel_size: 5,
+            open_iterations: 1,
+            close_iterations: 2,
+            min_area: 0.05,
+            max_area: 100.0,
+            min_aspect_ratio: 0.0,
+            max_aspect_ratio: f64::INFINITY,
+            min_fill: 0.0,
+            max_fill: 1.0,
+            max_targets: 8,
+            class_id: 0,
+            stream_mask: false,
+        }
+    }
+}
+
+/// Finds blobs of one color by thresholding in HSV.
+pub struct HsvObjectDetector {
+    options: HsvOptions,
+    kernel: Mat,
+    mask: Option<Mat>,
+}
+
+impl HsvObjectDetector {
+    pub fn new(options: HsvOptions) -> opencv::Result<Self> {
+        let kernel = imgproc::get_structuring_element(
+            imgproc::MORPH_ELLIPSE,
+            Size::new(options.kernel_size, options.kernel_size),
+            Point::new(-1, -1),
+        )?;
+        Ok(Self {
+            options,
+            kernel,
+            mask: None,
+        })
+    }
+
+    fn threshold(&self, image: &Mat) -> opencv::Result<Mat> {
+        let options = &self.options;
+        let mut hsv = Mat::default();
+        imgproc::cvt_color(image, &mut hsv, imgproc::COLOR_BGR2HSV, 0)?;
+        let range = |hue: [f64; 2]| -> opencv::Result<Mat> {
+            let mut mask = Mat::default();
+            opencv::core::in_range(
+                &hsv,
+                &Scalar::new(hue[0], options.saturation[0], options.value[0], 0.0),
+                &Scalar::new(hue[1], options.saturation[1], options.value[1], 0.0),
+                &mut mask,
+            )?;
+            Ok(mask)
+        };
+        let [min_hue, max_hue] = options.hue;
+        let mut mask = if min_hue <= max_hue {
+            range(options.hue)?
+        } else {
+            let mut mask = Mat::default();
+            opencv::core::bitwise_or(
+                &range([min_hue, 180.0])?,
+                &range([0.0, max_hue])?,
+                &mut mask,
+                &opencv::core::no_array(),
+            )?;
+            mask
+        };
+
+        for (operation, iterations) in [
+            (imgproc::MORPH_OPEN, options.open_iterations),
+            (imgproc::MORPH_CLOSE, options.close_iterations),
+        ] {
+            if iterations > 0 {
+                let mut morphed = Mat::default();
+                imgproc::morphology_ex(
+                    &mask,
+                    &mut morphed,
+                    operation,
+                    &self.kernel,
+                    Point::new(-1, -1),
+                    iterations,
+                    opencv::core::BORDER_CONSTANT,
+                    imgproc::morphology_default_border_value()?,
+                )?;
+                mask = morphed;
+            }
+        }
+        Ok(mask)
+    }
+}
+
+impl ObjectDetector for HsvObjectDetector {
+    fn detect_objects(
+        &mut self,
+        image: &Mat,
+        config_store: &CameraConfig,
+    ) -> Result<Vec<ObjectTarget>, PipelineError> {
+        let options = &self.options;
+        let mask = self.threshold(image)?;
+        let mut contours = VectorOfVectorOfPoint::new();
+        imgproc::find_contours(
+            &mask,
+            &mut contours,
+            imgproc::RETR_EXTERNAL,
+            imgproc::CHAIN_APPROX_SIMPLE,
+            Point::default(),
+        )?;
+
+        let image_area = (image.cols() * image.rows()) as f64;
+        let mut blobs = Vec::new();
+        for contour in contours {
+            let area = imgproc::contour_area(&contour, false)?;
+            let rect = imgproc::bounding_rect(&contour)?;
+            if rect.width == 0 || rect.height == 0 {
+                continue;
+            }
+            let percent = area / image_area * 100.0;
+            let aspect_ratio = rect.width as f64 / rect.height as f64;
+            let fill = area / (rect.width * rect.height) as f64;
+            if (options.min_area..=options.max_area).contains(&percent)
+                && (options.min_aspect_ratio..=options.max_aspect_ratio).contains(&aspect_ratio)
+                && (options.min_fill..=options.max_fill).contains(&fill)
+            {
+                blobs.push((rect, area, options.class_id, None));
+            }
+        }
+
+        self.mask = options.stream_mask.then_some(mask);
+        Ok(object_targets(blobs, options.max_targets, config_store)?)
+    }
+
+    fn debug_image(&self) -> Option<&Mat> {
+        self.mask.as_ref()
+    }
+}
+
+/// Output layouts of YOLO models.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum YoloFormat {
+    /// `[1, candidates, values]` with center x, center y, width, height, objectness then the class scores
+    Yolov5,
+    /// `[1, values, candidates]` with center x, center y, width, height then the class scores
+    Yolov8,
+}
+
+/// Candidates of a YOLO output with `shape` that score at least `threshold`, as the center x, center y, width and
+/// height of the box in model input pixels, the class ID and the score.
+fn yolo_candidates(
+    data: &[f32],
+    shape: &[i32],
+    format: Option<YoloFormat>,
+    threshold: f32,
+) -> Result<Vec<([f32; 4], u32, f32)>, PipelineError> {
+    if shape.len() != 3 {
+        return Err(PipelineError::Config(format!(
+            "expected a YOLO output with 3 dimensions, got {:?}",
+            shape
+        )));
+    }
+    let format = format.unwrap_or(if shape[1] < shape[2] {
+        YoloFormat::Yolov8
+    } else {
+        YoloFormat::Yolov5
+    });
+    let (candidates, values, classes_start) = match format {
+        YoloFormat::Yolov5 => (shape[1] as usize, shape[2] as usize, 5),
+        YoloFormat::Yolov8 => (shape[2] as usize, shape[1] as usize, 4),
+    };
+    if values <= classes_start || data.len() != candidates * values {
+        return Err(PipelineError::Config(format!(
+            "expected a {:?} output, got {:?}",
+            format, shape
+        )));
+    }
+    let value = |candidate: usize, index: usize| match format {
+        YoloFormat::Yolov5 => data[candidate * values + index],
+        YoloFormat::Yolov8 => data[index * candidates + candidate],
+    };
+
+    let mut found = Vec::new();
+    for candidate in 0..candidates {
+        let (class_id, class_score) = (classes_start..values)
+            .map(|index| (index - classes_start, value(candidate, index)))
+            .fold((0, f32::MIN), |best, x| if x.1 > best.1 { x } else { best });
+        let score = match format {
+            YoloFormat::Yolov5 => class_score * value(candidate, 4),
+            YoloFormat::Yolov8 => class_score,
+        };
+        if score >= threshold {
+            found.push((
+                [0, 1, 2, 3].map(|x| value(candidate, x)),
+                class_id as u32,
+                score,
+            ));
+        }
+    }
+    Ok(found)
+}
+
+/// How an image is scaled and padded to the model input without stretching it, the way YOLO models are trained.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Letterbox {
+    scale: f64,
+    /// Size of the image once scaled
+    size: Size,
+    /// Padding on the left and top
+    left: i32,
+    top: i32,
+}
+
+impl Letterbox {
+    fn new(image: Size, input: Size) -> Self {
+        let scale = (input.width as f64 / image.width as f64)
+            .min(input.height as f64 / image.height as f64);
+        let size = Size::new(
+            ((image.width as f64 * scale).round() as i32).min(input.width),
+            ((image.height as f64 * scale).round() as i32).min(input.height),
+        );
+        Self {
+            scale,
+            size,
+            left: (input.width - size.width) / 2,
+            top: (input.height - size.height) / 2,
+        }
+    }
+
+    /// `image` scaled and padded with grey to `input`
+    fn apply(&self, image: &Mat, input: Size) -> opencv::Result<Mat> {
+        let mut scaled = Mat::default();
+        imgproc::resize(
+            image,
+            &mut scaled,
+            self.size,
+            0.0,
+            0.0,
+            imgproc::INTER_LINEAR,
+        )?;
+        let mut padded = Mat::default();
+        opencv::core::copy_make_border(
+            &scaled,
+            &mut padded,
+            self.top,
+            input.height - self.size.height - self.top,
+            self.left,
+            input.width - self.size.width - self.left,
+            opencv::core::BORDER_CONSTANT,
+            Scalar::all(114.0),
+        )?;
+        Ok(padded)
+    }
+
+    /// Box in image pixels from its center x, center y, width and height in model input pixels
+    fn to_image(&self, [center_x, center_y, width, height]: [f32; 4]) -> Rect {
+        let [center_x, center_y, width, height] =
+            [center_x, center_y, width, height].map(|x| x as f64);
+        Rect::new(
+            ((center_x - width / 2.0 - self.left as f64) / self.scale).round() as i32,
+            ((center_y - height / 2.0 - self.top as f64) / self.scale).round() as i32,
+            (width / self.scale).round() as i32,
+            (height / self.scale).round() as i32,
+        )
+    }
+}
+
+/// Options of [`DnnObjectDetector`].
+#[derive(Deserialize, Debug, Clone)]
+pub struct DnnOptions {
+    /// ONNX file as exported by YOLOv5 or YOLOv8
+    pub model: String,
+    /// Layout of the model output. When not set it is told from the shape of the output, which has more candidates
+    /// than values per candidate: YOLOv5 puts them in rows and YOLOv8 in columns.
+    #[serde(default)]
+    pub format: Option<YoloFormat>,
+    /// Width and height the model was exported for
+    #[serde(default = "default_input_size")]
+    pub input_size: [i32; 2],
+    /// Names of the classes indexed by class ID, published with each target
+    #[serde(default)]
+    pub class_names: Vec<String>,
+    /// Minimum confidence, between 0 and 1
+    #[serde(default = "default_confidence_threshold")]
+    pub confidence_threshold: f32,
+    /// Overlap above which the less confident of two boxes is dropped
+    #[serde(default = "default_nms_threshold")]
+    pub nms_threshold: f32,
+    /// Left, top, width and height in pixels of the part of the image to look at, all of it if not set
+    #[serde(default)]
+    pub roi: Option<[i32; 4]>,
+    #[serde(default = "default_max_targets")]
+    pub max_targets: usize,
+}
+
+fn default_input_size() -> [i32; 2] {
+    [640, 640]
+}
+
+fn default_confidence_threshold() -> f32 {
+    0.5
+}
+
+fn default_nms_threshold() -> f32 {
+    0.45
+}
+
+fn default_max_targets() -> usize {
+    8
+}
+
+/// Runs a YOLO style ONNX model with OpenCV's DNN module on the CPU.
+pub struct DnnObjectDetector {
+    options: DnnOptions,
+    net: dnn::Net,
+}
+
+impl DnnObjectDetector {
+    pub fn new(options: DnnOptions) -> Result<Self, PipelineError> {
+        let mut net = dnn::read_net_from_onnx(&options.model).map_err(|e| {
+            PipelineError::Config(format!("can't load model {:?}: {}", options.model, e))
+        })?;
+        net.set_preferable_backend(dnn::DNN_BACKEND_OPENCV)?;
+        net.set_preferable_target(dnn::DNN_TARGET_CPU)?;
+        Ok(Self { options, net })
+    }
+
+    /// Part of `image` to run the model on, clamped to the image
+    fn roi(&self, image: &Mat) -> Result<Rect, PipelineError> {
+        let [x, y, width, height] = self
+            .options
+            .roi
+            .unwrap_or([0, 0, image.cols(), image.rows()]);
+        let (left, top) = (x.clamp(0, image.cols()), y.clamp(0, image.rows()));
+        let right = (x + width).clamp(left, image.cols());
+        let bottom = (y + height).clamp(top, image.rows());
+        if right == left || bottom == top {
+            return Err(PipelineError::Config(format!(
+                "roi {:?} is outside of the image",
+                self.options.roi
+            )));
+        }
+        Ok(Rect::new(left, top, right - left, bottom - top))
+    }
+}
+
+impl ObjectDetector for DnnObjectDetector {
+    fn detect_objects(
+        &mut self,
+        image: &Mat,
+        config_store: &CameraConfig,
+    ) -> Result<Vec<ObjectTarget>, PipelineError> {
+        let options = &self.options;
+        let roi = self.roi(image)?;
+        let cropped;
+        let input = if roi.width == image.cols() && roi.height == image.rows() {
+            image
+        } else {
+            cropped = Mat::roi(image, roi)?.try_clone()?;
+            &cropped
+        };
+        let [input_width, input_height] = options.input_size;
+        let input_size = Size::new(input_width, input_height);
+        let letterbox = Letterbox::new(input.size()?, input_size);
+        let blob = dnn::blob_from_image(
+            &letterbox.apply(input, input_size)?,
+            1.0 / 255.0,
+            input_size,
+            Scalar::default(),
+            true,
+            false,
+            CV_32F,
+        )?;
+        self.net.set_input(&blob, "", 1.0, Scalar::default())?;
+        let mut outputs = VectorOfMat::new();
+        let names = self.net.get_unconnected_out_layers_names()?;
+        self.net.forward(&mut outputs, &names)?;
+        let output = outputs.get(0)?;
+
+        let mut boxes = VectorOfRect::new();
+        let mut scores = VectorOff32::new();
+        let mut class_ids = Vec::new();
+        for (candidate, class_id, score) in yolo_candidates(
+            output.data_typed::<f32>()?,
+            &output.mat_size(),
+            options.format,
+            options.confidence_threshold,
+        )? {
+            let rect = letterbox.to_image(candidate);
+            boxes.push(Rect::new(
+                rect.x + roi.x,
+                rect.y + roi.y,
+                rect.width,
+                rect.height,
+            ));
+            scores.push(score);
+            class_ids.push(class_id);
+        }
+
+        let mut kept = VectorOfi32::new();
+        dnn::nms_boxes_def(
+            &boxes,
+            &scores,
+            options.confidence_threshold,
+            options.nms_threshold,
+            &mut kept,
+        )?;
+        let mut blobs = Vec::new();
+        for index in kept {
+            let index = index as usize;
+            let rect = boxes.get(index)?;
+            blobs.push((
+                rect,
+                rect.area() as f64,
+                class_ids[index],
+                Some(scores.get(index)? as f64),
+            ));
+        }
+        let targets = object_targets(blobs, options.max_targets, config_store)?;
+        Ok(name_classes(targets, &options.class_names))
+    }
+}
+
+/// Names each of `targets` by its class ID, which indexes `class_names`
+fn name_classes(mut targets: Vec<ObjectTarget>, class_names: &[String]) -> Vec<ObjectTarget> {
+    for target in &mut targets {
+        target.class_name = class_names.get(target.class_id as usize).cloned();
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn letterbox_keeps_the_aspect_ratio() {
+        let letterbox = Letterbox::new(Size::new(1280, 720), Size::new(640, 640));
+        assert_eq!(letterbox.scale, 0.5);
+        assert_eq!(letterbox.size, Size::new(640, 360));
+        assert_eq!((letterbox.left, letterbox.top), (0, 140));
+        assert_eq!(
+            letterbox.to_image([320.0, 320.0, 64.0, 32.0]),
+            Rect::new(576, 328, 128, 64)
+        );
+    }
+
+    #[test]
+    fn letterbox_pads_narrow_images_on_the_sides() {
+        let letterbox = Letterbox::new(Size::new(480, 640), Size::new(640, 640));
+        assert_eq!(letterbox.scale, 1.0);
+        assert_eq!((letterbox.left, letterbox.top), (80, 0));
+        assert_eq!(
+            letterbox.to_image([400.0, 100.0, 20.0, 40.0]),
+            Rect::new(310, 80, 20, 40)
+        );
+    }
+
+    /// Two classes, with the second candidate the only one above the threshold
+    fn candidates() -> [[f32; 6]; 10] {
+        let mut candidates = [[0.0; 6]; 10];
+        candidates[1] = [100.0, 200.0, 30.0, 40.0, 0.2, 0.9];
+        candidates[3] = [10.0, 20.0, 5.0, 5.0, 0.3, 0.1];
+        candidates
+    }
+
+    #[test]
+    fn yolov5_rows() {
+        // Objectness goes between the box and the class scores
+        let data = candidates()
+            .iter()
+            .flat_map(|[x, y, w, h, a, b]| [*x, *y, *w, *h, 0.5, *a, *b])
+            .collect::<Vec<_>>();
+        for format in [None, Some(YoloFormat::Yolov5)] {
+            assert_eq!(
+                yolo_candidates(&data, &[1, 10, 7], format, 0.4).unwrap(),
+                vec![([100.0, 200.0, 30.0, 40.0], 1, 0.45)]
+            );
+        }
+    }
+
+    #[test]
+    fn yolov8_columns() {
+        let candidates = candidates();
+        let data = (0..6)
+            .flat_map(|value| candidates.iter().map(move |x| x[value]))
+            .collect::<Vec<_>>();
+        for format in [None, Some(YoloFormat::Yolov8)] {
+            assert_eq!(
+                yolo_candidates(&data, &[1, 6, 10], format, 0.4).unwrap(),
+                vec![([100.0, 200.0, 30.0, 40.0], 1, 0.9)]
+            );
+        }
+    }
+
+    #[test]
+    fn names_classes_by_id() {
+        let target = |class_id| ObjectTarget {
+            class_id,
+            class_name: None,
+            confidence: Some(0.9),
+            yaw: 0.0,
+            pitch: 0.0,
+            area: 1.0,
+            bounding_box: [0.0, 0.0, 10.0, 10.0],
+            position: None,
+            corners: None,
+            camera_to_target: None,
+        };
+        let class_names = ["note".to_string(), "robot".to_string()];
+        let named = name_classes(vec![target(1), target(0), target(2)], &class_names);
+        assert_eq!(
+            named
+                .iter()
+                .map(|x| x.class_name.as_deref())
+                .collect::<Vec<_>>(),
+            vec![Some("robot"), Some("note"), None]
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_format() {
+        assert!(yolo_candidates(&[0.0; 40], &[1, 10, 4], Some(YoloFormat::Yolov5), 0.5).is_err());
+        assert!(yolo_candidates(&[0.0; 40], &[1, 40], None, 0.5).is_err());
+    }
+}
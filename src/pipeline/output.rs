@@ -0,0 +1,100 @@
+use std::time::Instant;
+
+use super::{error::PipelineError, object_detector::ObjectTarget, targeting::TagTarget};
+use crate::{
+    config::CameraConfig,
+    detection_packet::DetectionPacket,
+    limelight::LimelightResult,
+    millis_between,
+    photonvision::PhotonPipelineResult,
+    pose_packet::PosePacket,
+    types::{CameraPoseObservation, FiducialImageObservation},
+    NtData,
+};
+
+/// Everything the pipeline found in one frame.
+pub struct FrameResult<'a> {
+    pub camera: usize,
+    /// NetworkTables server time in microseconds when the frame was captured
+    pub time: u32,
+    pub captured: Instant,
+    pub detected: Instant,
+    pub capture_latency_ms: f64,
+    pub observations: &'a [FiducialImageObservation],
+    pub targets: &'a [TagTarget],
+    /// Only with an object detector
+    pub objects: Option<&'a [ObjectTarget]>,
+    pub pose: Option<&'a CameraPoseObservation>,
+    /// Only with tracking enabled
+    pub filtered_pose: Option<&'a CameraPoseObservation>,
+    pub config: &'a CameraConfig,
+}
+
+pub trait OutputSink {
+    /// Messages for the NetworkTables task about `frame`
+    fn output(&mut self, frame: &FrameResult) -> Result<Vec<NtData>, PipelineError>;
+}
+
+/// Binary packets under `/watson/<camera>/`, see [`crate::pose_packet`] and [`crate::detection_packet`]. Objects are
+/// published to `/watson/<camera>/objects` as one double array, eleven values per object in the order of
+/// [`ObjectTarget::to_array`], with their corners, poses and class names under `objects/corners`, `objects/poses` and
+/// `objects/classes`.
+pub struct WatsonOutput;
+
+impl OutputSink for WatsonOutput {
+    fn output(&mut self, frame: &FrameResult) -> Result<Vec<NtData>, PipelineError> {
+        let detections = DetectionPacket {
+            time: frame.time as u64,
+            detections: frame.observations.to_vec(),
+        };
+        let mut messages = vec![
+            NtData::Detections(frame.camera, detections.to_bytes()),
+            NtData::Targets(frame.camera, frame.targets.to_vec()),
+        ];
+        if let Some(objects) = frame.objects {
+            messages.push(NtData::Objects(frame.camera, objects.to_vec()));
+        }
+        let latency_ms = Some(millis_between(frame.captured, Instant::now()));
+        if let Some(pose) = frame.pose {
+            let packet = PosePacket::new(pose, frame.time, latency_ms)?;
+            messages.push(NtData::Pose(frame.camera, packet.to_bytes()));
+        }
+        if let Some(filtered) = frame.filtered_pose {
+            let packet = PosePacket::new(filtered, frame.time, latency_ms)?;
+            messages.push(NtData::FilteredPose(frame.camera, packet.to_bytes()));
+        }
+        Ok(messages)
+    }
+}
+
+/// The PhotonVision NetworkTables API, see [`crate::photonvision`].
+pub struct PhotonVisionOutput;
+
+impl OutputSink for PhotonVisionOutput {
+    fn output(&mut self, frame: &FrameResult) -> Result<Vec<NtData>, PipelineError> {
+        let result = PhotonPipelineResult::new(
+            millis_between(frame.captured, Instant::now()),
+            frame.observations,
+            frame.targets,
+            frame.pose,
+            frame.config,
+        );
+        Ok(vec![NtData::Photon(frame.camera, result)])
+    }
+}
+
+/// The Limelight NetworkTables API, see [`crate::limelight`].
+pub struct LimelightOutput;
+
+impl OutputSink for LimelightOutput {
+    fn output(&mut self, frame: &FrameResult) -> Result<Vec<NtData>, PipelineError> {
+        let result = LimelightResult::new(
+            frame.targets.to_vec(),
+            frame.pose,
+            frame.config,
+            millis_between(frame.detected, Instant::now()),
+            frame.capture_latency_ms,
+        );
+        Ok(vec![NtData::Limelight(frame.camera, result)])
+    }
+}
@@ -0,0 +1,131 @@
+use nalgebra::Isometry3;
+use thiserror::Error;
+
+use super::camera_pose_estimator::measurement_std_devs;
+use crate::{config::CameraConfig, types::CameraPoseObservation};
+
+/// Why a pose was found to be implausible.
+#[derive(Debug, Clone, Copy, PartialEq, Error)]
+pub enum PoseRejection {
+    #[error("robot at ({0:.2}, {1:.2}) is outside the field")]
+    OutsideField(f64, f64),
+    #[error("robot height {0:.2}m is out of range")]
+    Height(f64),
+    #[error("robot is tilted {0:.1} degrees")]
+    Tilted(f64),
+}
+
+impl PoseRejection {
+    /// Identifies the reason in the binary pose format, 0 means not rejected
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::OutsideField(..) => 1,
+            Self::Height(_) => 2,
+            Self::Tilted(_) => 3,
+        }
+    }
+}
+
+pub trait PoseFilter {
+    /// Checks `observation` for plausibility. May choose between the two candidate poses of an ambiguous solve.
+    fn filter_pose(
+        &mut self,
+        observation: &mut CameraPoseObservation,
+        config_store: &CameraConfig,
+    ) -> Result<(), PoseRejection>;
+}
+
+/// Rejects poses that put the robot outside the field, above or below the floor or tipped over. Without a
+/// `robot_to_camera` only the field bounds are checked.
+pub struct FieldBoundsPoseFilter;
+
+impl FieldBoundsPoseFilter {
+    fn check(
+        field_to_camera: &Isometry3<f64>,
+        config_store: &CameraConfig,
+    ) -> Result<(), PoseRejection> {
+        let Some(filter) = &config_store.pose_filter else {
+            return Ok(());
+        };
+        let robot = config_store.robot_pose(field_to_camera);
+        let (x, y, z) = (
+            robot.translation.x,
+            robot.translation.y,
+            robot.translation.z,
+        );
+        if let Some(field) = &config_store.field {
+            let margin = filter.field_margin_m;
+            if x < -margin
+                || y < -margin
+                || x > field.length_m + margin
+                || y > field.width_m + margin
+            {
+                return Err(PoseRejection::OutsideField(x, y));
+            }
+        }
+        if config_store.robot_to_camera.is_none() {
+            return Ok(());
+        }
+        if z < filter.min_height_m || z > filter.max_height_m {
+            return Err(PoseRejection::Height(z));
+        }
+        let (roll, pitch, _) = robot.rotation.euler_angles();
+        let tilt = roll.abs().max(pitch.abs()).to_degrees();
+        if tilt > filter.max_roll_pitch_deg {
+            return Err(PoseRejection::Tilted(tilt));
+        }
+        Ok(())
+    }
+}
+
+impl PoseFilter for FieldBoundsPoseFilter {
+    fn filter_pose(
+        &mut self,
+        observation: &mut CameraPoseObservation,
+        config_store: &CameraConfig,
+    ) -> Result<(), PoseRejection> {
+        let result_0 = Self::check(&observation.pose_0, config_store);
+        let Some(pose_1) = observation.pose_1 else {
+            return result_0;
+        };
+        match (result_0, Self::check(&pose_1, config_store)) {
+            (Ok(()), Err(_)) => {
+                observation.pose_1 = None;
+                observation.error_1 = None;
+                Ok(())
+            }
+            (Err(_), Ok(())) => {
+                let error_1 = observation.error_1.take().unwrap_or(observation.error_0);
+                let ambiguity = if observation.error_0 > 0.0 {
+                    error_1 / observation.error_0
+                } else {
+                    1.0
+                };
+                observation.pose_0 = pose_1;
+                observation.error_0 = error_1;
+                observation.pose_1 = None;
+                if observation.tag_residuals.len() == 1 {
+                    observation.tag_residuals[0] = observation.error_0;
+                }
+                // Only single tag solves are ambiguous, so the distance is to that tag
+                if let Some(tag) = config_store
+                    .tag_layout
+                    .tags
+                    .iter()
+                    .find(|x| observation.tag_ids.first() == Some(&x.id))
+                {
+                    observation.std_devs = measurement_std_devs(
+                        &config_store.std_devs,
+                        (tag.pose.translation.vector - pose_1.translation.vector).norm(),
+                        1,
+                        observation.error_0,
+                        ambiguity,
+                        None,
+                    );
+                }
+                Ok(())
+            }
+            (result_0, _) => result_0,
+        }
+    }
+}
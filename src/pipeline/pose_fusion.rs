@@ -0,0 +1,230 @@
+use std::time::Instant;
+
+use nalgebra::{Isometry3, Vector3};
+use opencv::{
+    core::{Mat, Point2d, Vec2d, CV_64F},
+    prelude::*,
+    types::{VectorOfPoint2d, VectorOfVec2d, VectorOfVec3d, VectorOff64},
+};
+
+use crate::{
+    config::CameraConfig,
+    types::{
+        isometry_from_opencv, translation_to_opencv, CameraPoseObservation,
+        FiducialImageObservation,
+    },
+};
+
+use super::{
+    camera_pose_estimator::fiducial_corners,
+    error::PipelineError,
+    reprojection::{refine_pose, reprojection_error, View},
+};
+
+/// Everything a single camera saw in one frame.
+#[derive(Debug, Clone)]
+pub struct FusionInput {
+    pub camera: usize,
+    pub received: Instant,
+    /// NetworkTables server time of the frame
+    pub time: u32,
+    pub observations: Vec<FiducialImageObservation>,
+    /// Field relative pose of the camera and its error, from the single camera solve. `None` if the pose filter found it
+    /// implausible.
+    pub camera_pose: Option<(Isometry3<f64>, f64)>,
+}
+
+/// Solves for the pose of the robot using the corners seen by every camera in one joint PnP.
+pub struct MultiCameraPoseFusion {
+    pub max_iterations: usize,
+}
+
+impl Default for MultiCameraPoseFusion {
+    fn default() -> Self {
+        Self { max_iterations: 20 }
+    }
+}
+
+impl MultiCameraPoseFusion {
+    /// `inputs` should hold at most one frame per camera. Cameras without a `robot_to_camera` are ignored.
+    /// The returned observation is of the robot, not of a camera.
+    pub fn fuse(
+        &mut self,
+        inputs: &[FusionInput],
+        cameras: &[CameraConfig],
+    ) -> Result<Option<CameraPoseObservation>, PipelineError> {
+        let mut views = Vec::new();
+        let mut tag_ids = Vec::new();
+        let mut tag_corners = Vec::new();
+        let mut initial: Option<(Isometry3<f64>, f64)> = None;
+        for input in inputs {
+            let camera = &cameras[input.camera];
+            let Some(robot_to_camera) = camera.robot_to_camera else {
+                continue;
+            };
+            let mut view = View {
+                camera_matrix: &camera.camera_matrix,
+                distortion_coefficients: &camera.distortion_coefficients,
+                body_to_camera: robot_to_camera,
+                object_points: Vec::new(),
+                image_points: Vec::new(),
+            };
+            for observation in &input.observations {
+                if let Some(tag) = camera
+                    .tag_layout
+                    .tags
+                    .iter()
+                    .find(|x| x.id == observation.tag_id)
+                {
+                    let object_points = fiducial_corners(tag.pose, camera.fiducial_size_m);
+                    view.object_points.extend(object_points);
+                    view.image_points.extend(observation.corners);
+                    if !tag_ids.contains(&observation.tag_id) {
+                        tag_ids.push(observation.tag_id);
+                    }
+                    tag_corners.push((
+                        observation.tag_id,
+                        views.len(),
+                        object_points,
+                        observation.corners,
+                    ));
+                }
+            }
+            if view.object_points.is_empty() {
+                continue;
+            }
+            if let Some((field_to_camera, error)) = input.camera_pose {
+                if initial.map_or(true, |(_, best_error)| error < best_error) {
+                    initial = Some((field_to_camera * robot_to_camera.inverse(), error));
+                }
+            }
+            views.push(view);
+        }
+
+        // The single camera poses are only a starting point when a camera could solve on its own, so the joint solve
+        // is tried too and whichever fits every camera best is refined
+        let mut seeds = Vec::new();
+        for seed in [initial.map(|(pose, _)| pose), joint_seed(&views)?]
+            .into_iter()
+            .flatten()
+        {
+            seeds.push((seed, joint_cost(&views, &seed)?));
+        }
+        let Some((initial, _)) = seeds.into_iter().min_by(|a, b| a.1.total_cmp(&b.1)) else {
+            return Ok(None);
+        };
+        let Some(refinement) = refine_pose(initial, &views, self.max_iterations)? else {
+            return Ok(None);
+        };
+        // A tag seen by more than one camera gets the RMS of its errors in each
+        let mut tag_residuals = Vec::with_capacity(tag_ids.len());
+        for tag_id in &tag_ids {
+            let mut squared_error = 0.0;
+            let mut count = 0;
+            for (_, view, object_points, image_points) in
+                tag_corners.iter().filter(|(id, ..)| id == tag_id)
+            {
+                let view = &views[*view];
+                let error = reprojection_error(
+                    &(refinement.pose * view.body_to_camera),
+                    object_points,
+                    image_points,
+                    view.camera_matrix,
+                    view.distortion_coefficients,
+                )?;
+                squared_error += error * error;
+                count += 1;
+            }
+            tag_residuals.push((squared_error / count as f64).sqrt());
+        }
+        Ok(Some(CameraPoseObservation {
+            tag_ids,
+            pose_0: refinement.pose,
+            error_0: refinement.error,
+            pose_1: None,
+            error_1: None,
+            tag_residuals,
+            rejected_tag_ids: Vec::new(),
+            std_devs: std::array::from_fn(|i| refinement.covariance[(i, i)].sqrt()),
+            covariance: Some(refinement.covariance),
+            rejection: None,
+        }))
+    }
+}
+
+/// Sum of the squared pixel errors of every view with the body at `field_to_body`.
+fn joint_cost(views: &[View], field_to_body: &Isometry3<f64>) -> opencv::Result<f64> {
+    let mut cost = 0.0;
+    for view in views {
+        cost += view
+            .residuals(field_to_body)?
+            .iter()
+            .map(|x| x * x)
+            .sum::<f64>();
+    }
+    Ok(cost)
+}
+
+/// Solves for the body with one SQPNP over the corners seen by every camera. Each corner is undistorted and its ray
+/// rotated into the frame of the camera that sees the most corners, as if every camera sat at the same point on the
+/// body. That is close enough to seed [`refine_pose`], which accounts for where each camera really is. Rays that point
+/// away from the reference camera are left out.
+fn joint_seed(views: &[View]) -> opencv::Result<Option<Isometry3<f64>>> {
+    let Some(reference) = views.iter().max_by_key(|x| x.object_points.len()) else {
+        return Ok(None);
+    };
+    let camera_to_reference = reference.body_to_camera.rotation.inverse();
+    let mut object_points = VectorOfVec3d::new();
+    let mut image_points = VectorOfVec2d::new();
+    for view in views {
+        let distorted = view
+            .image_points
+            .iter()
+            .map(|[x, y]| Point2d::new(*x, *y))
+            .collect::<VectorOfPoint2d>();
+        let mut normalized = VectorOfPoint2d::new();
+        opencv::calib3d::undistort_points_def(
+            &distorted,
+            &mut normalized,
+            view.camera_matrix,
+            view.distortion_coefficients,
+        )?;
+        let rotation = camera_to_reference * view.body_to_camera.rotation;
+        for (object_point, normalized) in view.object_points.iter().zip(normalized) {
+            let ray = rotation * Vector3::new(1.0, -normalized.x, -normalized.y);
+            if ray.x < 0.1 {
+                continue;
+            }
+            object_points.push(translation_to_opencv(*object_point));
+            image_points.push(Vec2d::from_array([-ray.y / ray.x, -ray.z / ray.x]));
+        }
+    }
+    if object_points.len() < 4 {
+        return Ok(None);
+    }
+
+    let camera_matrix = Mat::eye(3, 3, CV_64F)?.to_mat()?;
+    let mut rvecs = VectorOfVec3d::new();
+    let mut tvecs = VectorOfVec3d::new();
+    let mut errors = VectorOff64::new();
+    opencv::calib3d::solve_pnp_generic(
+        &object_points,
+        &image_points,
+        &camera_matrix,
+        &opencv::core::no_array(),
+        &mut rvecs,
+        &mut tvecs,
+        false,
+        opencv::calib3d::SolvePnPMethod::SOLVEPNP_SQPNP,
+        &opencv::core::no_array(),
+        &opencv::core::no_array(),
+        &mut errors,
+    )?;
+    if tvecs.len() < 1 || rvecs.len() < 1 {
+        return Ok(None);
+    }
+    let camera_to_field = isometry_from_opencv(tvecs.get(0)?, rvecs.get(0)?);
+    Ok(Some(
+        camera_to_field.inverse() * reference.body_to_camera.inverse(),
+    ))
+}
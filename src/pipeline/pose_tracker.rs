@@ -0,0 +1,173 @@
+use std::{
+    f64::consts::{PI, TAU},
+    time::{Duration, Instant},
+};
+
+use nalgebra::{
+    Isometry3, Matrix2, Matrix3, Matrix3x6, Matrix6, Translation3, UnitQuaternion, Vector3, Vector6,
+};
+
+use crate::{
+    config::{CameraConfig, TrackingConfig},
+    types::CameraPoseObservation,
+};
+
+/// Wraps an angle to [-pi, pi)
+fn wrap_angle(angle: f64) -> f64 {
+    (angle + PI).rem_euclid(TAU) - PI
+}
+
+/// Planar pose and velocity of the robot: x, y, yaw, then their rates.
+struct Track {
+    mean: Vector6<f64>,
+    covariance: Matrix6<f64>,
+    updated: Instant,
+    /// Measurements dropped by the gate since the last one that was accepted
+    gated: usize,
+}
+
+/// Constant velocity Kalman filter on the field relative x, y and yaw of the robot.
+#[derive(Default)]
+pub struct PoseTracker {
+    track: Option<Track>,
+}
+
+impl PoseTracker {
+    /// Feeds the filter a pose solved from a frame captured at `captured`. Returns the filtered pose in the same form,
+    /// or `None` if the measurement was too far from the prediction to be believed.
+    pub fn update(
+        &mut self,
+        observation: &CameraPoseObservation,
+        captured: Instant,
+        config: &TrackingConfig,
+        config_store: &CameraConfig,
+    ) -> Option<CameraPoseObservation> {
+        let reset_timeout = Duration::from_millis(config.reset_timeout_ms);
+        if self.track.as_ref().is_some_and(|track| {
+            captured.saturating_duration_since(track.updated) > reset_timeout
+                || track.gated >= config.max_gated
+        }) {
+            self.track = None;
+        }
+
+        let noise = Matrix3::from_diagonal(&Vector3::new(
+            observation.std_devs[0].max(1e-6).powi(2),
+            observation.std_devs[1].max(1e-6).powi(2),
+            observation.std_devs[5].max(1e-6).powi(2),
+        ));
+        let measure = |field_to_camera: &Isometry3<f64>| {
+            let robot = config_store.robot_pose(field_to_camera);
+            let (_, _, yaw) = robot.rotation.euler_angles();
+            Vector3::new(robot.translation.x, robot.translation.y, yaw)
+        };
+        let candidates = [
+            Some((observation.pose_0, observation.error_0)),
+            observation.pose_1.zip(observation.error_1),
+        ];
+
+        let (pose, error) = match &mut self.track {
+            None => {
+                let (pose, error) = candidates[0].unwrap();
+                let mut covariance = Matrix6::identity();
+                covariance.fixed_view_mut::<3, 3>(0, 0).copy_from(&noise);
+                self.track = Some(Track {
+                    mean: measure(&pose).push(0.0).push(0.0).push(0.0),
+                    covariance,
+                    updated: captured,
+                    gated: 0,
+                });
+                (pose, error)
+            }
+            Some(track) => {
+                let dt = captured
+                    .saturating_duration_since(track.updated)
+                    .as_secs_f64();
+                let mut transition = Matrix6::identity();
+                transition.fixed_view_mut::<3, 3>(0, 3).fill_diagonal(dt);
+                let white_acceleration = Matrix2::new(
+                    dt.powi(4) / 4.0,
+                    dt.powi(3) / 2.0,
+                    dt.powi(3) / 2.0,
+                    dt.powi(2),
+                );
+                let mut process_noise = Matrix6::zeros();
+                for (axis, std_dev) in [
+                    config.acceleration_std_dev,
+                    config.acceleration_std_dev,
+                    config.angular_acceleration_std_dev,
+                ]
+                .into_iter()
+                .enumerate()
+                {
+                    for (i, j) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                        process_noise[(axis + i * 3, axis + j * 3)] =
+                            white_acceleration[(i, j)] * std_dev.powi(2);
+                    }
+                }
+                let mut mean = transition * track.mean;
+                mean[2] = wrap_angle(mean[2]);
+                let covariance =
+                    transition * track.covariance * transition.transpose() + process_noise;
+
+                let measurement_model = Matrix3x6::identity();
+                let innovation_covariance =
+                    measurement_model * covariance * measurement_model.transpose() + noise;
+                let innovation_covariance_inverse = innovation_covariance.try_inverse()?;
+                // Take whichever candidate of an ambiguous solve agrees with the prediction
+                let (pose, error, innovation, distance) = candidates
+                    .into_iter()
+                    .flatten()
+                    .map(|(pose, error)| {
+                        let mut innovation = measure(&pose) - measurement_model * mean;
+                        innovation[2] = wrap_angle(innovation[2]);
+                        let distance = innovation
+                            .dot(&(innovation_covariance_inverse * innovation))
+                            .sqrt();
+                        (pose, error, innovation, distance)
+                    })
+                    .min_by(|a, b| a.3.total_cmp(&b.3))?;
+                if distance > config.gate_sigma {
+                    track.gated += 1;
+                    return None;
+                }
+
+                let gain =
+                    covariance * measurement_model.transpose() * innovation_covariance_inverse;
+                track.mean = mean + gain * innovation;
+                track.mean[2] = wrap_angle(track.mean[2]);
+                track.covariance = (Matrix6::identity() - gain * measurement_model) * covariance;
+                track.updated = captured;
+                track.gated = 0;
+                (pose, error)
+            }
+        };
+
+        // Only x, y and yaw are filtered, the rest of the pose comes from the measurement
+        let track = self.track.as_ref().unwrap();
+        let measured_robot = config_store.robot_pose(&pose);
+        let (roll, pitch, _) = measured_robot.rotation.euler_angles();
+        let robot = Isometry3::from_parts(
+            Translation3::new(track.mean[0], track.mean[1], measured_robot.translation.z),
+            UnitQuaternion::from_euler_angles(roll, pitch, track.mean[2]),
+        );
+        let mut std_devs = observation.std_devs;
+        std_devs[0] = track.covariance[(0, 0)].sqrt();
+        std_devs[1] = track.covariance[(1, 1)].sqrt();
+        std_devs[5] = track.covariance[(2, 2)].sqrt();
+        Some(CameraPoseObservation {
+            tag_ids: observation.tag_ids.clone(),
+            pose_0: match config_store.robot_to_camera {
+                Some(robot_to_camera) => robot * robot_to_camera,
+                None => robot,
+            },
+            error_0: error,
+            pose_1: None,
+            error_1: None,
+            tag_residuals: observation.tag_residuals.clone(),
+            rejected_tag_ids: observation.rejected_tag_ids.clone(),
+            covariance: None,
+            std_devs,
+            rejection: observation.rejection,
+        })
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+
+use serde::{de::DeserializeOwned, Deserialize};
+
+use super::{
+    camera_pose_estimator::{CameraPoseEstimator, MultiTargetCameraPoseEstimator},
+    capture::{Capture, DefaultCapture, GStreamerCapture, TestCapture},
+    error::PipelineError,
+    fiducial_detector::{ArucoFiducialDetector, FiducialDetector},
+    object_detector::{DnnObjectDetector, HsvObjectDetector, ObjectDetector},
+    output::{LimelightOutput, OutputSink, PhotonVisionOutput, WatsonOutput},
+    retroreflective::RetroreflectiveDetector,
+    robot_heading::RobotHeading,
+};
+use crate::config::{CameraConfig, StageConfig};
+
+/// What constructors get besides their options.
+pub struct StageEnv {
+    pub robot_heading: Option<RobotHeading>,
+}
+
+pub type Constructor<T> = fn(&StageConfig, &StageEnv) -> Result<Box<T>, PipelineError>;
+
+/// Parses the options of `config` for its backend.
+pub fn options<T: DeserializeOwned>(config: &StageConfig) -> Result<T, PipelineError> {
+    serde_json::from_value(config.options.clone().into())
+        .map_err(|e| PipelineError::Config(format!("{} options: {}", config.backend, e)))
+}
+
+/// The stages of one camera pipeline.
+pub struct Stages {
+    pub capture: Box<dyn Capture>,
+    pub detector: Option<Box<dyn FiducialDetector>>,
+    pub object_detector: Option<Box<dyn ObjectDetector>>,
+    pub pose_estimator: Box<dyn CameraPoseEstimator>,
+    pub outputs: Vec<Box<dyn OutputSink>>,
+}
+
+/// The stages of every pipeline of a camera, see [`crate::config::CameraConfig::presets`]. They are all built when the
+/// camera starts so switching between them takes effect on the next frame.
+pub struct PresetStages {
+    active: usize,
+    /// The error of pipelines that could not be built
+    pipelines: Vec<Result<Stages, String>>,
+}
+
+impl PresetStages {
+    /// Stages of the active pipeline
+    pub fn stages(&mut self) -> &mut Stages {
+        match &mut self.pipelines[self.active] {
+            Ok(stages) => stages,
+            Err(_) => unreachable!("only pipelines that were built become active"),
+        }
+    }
+
+    /// Makes `index` the active pipeline. The capture session is handed over when both use the same capture, so
+    /// switching doesn't wait for the camera to restart, and closed otherwise. Leaves the active pipeline alone on
+    /// errors.
+    pub fn switch(
+        &mut self,
+        pipelines: &[CameraConfig],
+        index: usize,
+    ) -> Result<(), PipelineError> {
+        match self.pipelines.get(index) {
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(PipelineError::Config(e.clone())),
+            None => return Err(PipelineError::Config(format!("no pipeline {}", index))),
+        }
+        if index == self.active {
+            return Ok(());
+        }
+        let (previous, config) = (&pipelines[self.active], &pipelines[index]);
+        if previous.capture.backend == config.capture.backend
+            && previous.capture.options == config.capture.options
+        {
+            let (low, high) = (self.active.min(index), self.active.max(index));
+            let (left, right) = self.pipelines.split_at_mut(high);
+            if let (Ok(low), Ok(high)) = (&mut left[low], &mut right[0]) {
+                std::mem::swap(&mut low.capture, &mut high.capture);
+            }
+        } else {
+            self.stages().capture.reopen();
+        }
+        self.active = index;
+        Ok(())
+    }
+}
+
+/// Builds the stages of a camera pipeline from the names in its config.
+pub struct Registry {
+    captures: HashMap<&'static str, Constructor<dyn Capture>>,
+    detectors: HashMap<&'static str, Constructor<dyn FiducialDetector>>,
+    object_detectors: HashMap<&'static str, Constructor<dyn ObjectDetector>>,
+    pose_estimators: HashMap<&'static str, Constructor<dyn CameraPoseEstimator>>,
+    outputs: HashMap<&'static str, Constructor<dyn OutputSink>>,
+}
+
+impl std::fmt::Debug for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Registry")
+            .field("captures", &self.captures.keys())
+            .field("detectors", &self.detectors.keys())
+            .field("object_detectors", &self.object_detectors.keys())
+            .field("pose_estimators", &self.pose_estimators.keys())
+            .field("outputs", &self.outputs.keys())
+            .finish()
+    }
+}
+
+fn build<T: ?Sized>(
+    constructors: &HashMap<&'static str, Constructor<T>>,
+    stage: &str,
+    config: &StageConfig,
+    env: &StageEnv,
+) -> Result<Box<T>, PipelineError> {
+    let constructor = constructors.get(config.backend.as_str()).ok_or_else(|| {
+        let mut known = constructors.keys().copied().collect::<Vec<_>>();
+        known.sort();
+        PipelineError::Config(format!(
+            "unknown {} backend {:?}, expected one of {}",
+            stage,
+            config.backend,
+            known.join(", ")
+        ))
+    })?;
+    constructor(config, env)
+}
+
+impl Registry {
+    pub fn capture(
+        &self,
+        config: &StageConfig,
+        env: &StageEnv,
+    ) -> Result<Box<dyn Capture>, PipelineError> {
+        build(&self.captures, "capture", config, env)
+    }
+
+    pub fn detector(
+        &self,
+        config: &StageConfig,
+        env: &StageEnv,
+    ) -> Result<Box<dyn FiducialDetector>, PipelineError> {
+        build(&self.detectors, "detector", config, env)
+    }
+
+    pub fn object_detector(
+        &self,
+        config: &StageConfig,
+        env: &StageEnv,
+    ) -> Result<Box<dyn ObjectDetector>, PipelineError> {
+        build(&self.object_detectors, "object detector", config, env)
+    }
+
+    pub fn pose_estimator(
+        &self,
+        config: &StageConfig,
+        env: &StageEnv,
+    ) -> Result<Box<dyn CameraPoseEstimator>, PipelineError> {
+        build(&self.pose_estimators, "pose estimator", config, env)
+    }
+
+    pub fn outputs(
+        &self,
+        configs: &[StageConfig],
+        env: &StageEnv,
+    ) -> Result<Vec<Box<dyn OutputSink>>, PipelineError> {
+        configs
+            .iter()
+            .map(|config| build(&self.outputs, "output", config, env))
+            .collect()
+    }
+
+    pub fn stages(&self, config: &CameraConfig, env: &StageEnv) -> Result<Stages, PipelineError> {
+        Ok(Stages {
+            capture: self.capture(&config.capture, env)?,
+            detector: config
+                .detector
+                .as_ref()
+                .map(|x| self.detector(x, env))
+                .transpose()?,
+            object_detector: config
+                .object_detector
+                .as_ref()
+                .map(|x| self.object_detector(x, env))
+                .transpose()?,
+            pose_estimator: self.pose_estimator(&config.pose_estimator, env)?,
+            outputs: self.outputs(&config.outputs, env)?,
+        })
+    }
+
+    /// Builds the stages of every pipeline of a camera, starting on `active`. Pipelines other than `active` that fail
+    /// to build only fail when switched to.
+    pub fn preset_stages(
+        &self,
+        pipelines: &[CameraConfig],
+        active: usize,
+        env: &StageEnv,
+    ) -> Result<PresetStages, PipelineError> {
+        let mut built = Vec::with_capacity(pipelines.len());
+        for (index, config) in pipelines.iter().enumerate() {
+            built.push(match self.stages(config, env) {
+                Ok(stages) => Ok(stages),
+                Err(e) if index == active => return Err(e),
+                Err(e) => Err(e.to_string()),
+            });
+        }
+        Ok(PresetStages {
+            active,
+            pipelines: built,
+        })
+    }
+
+    pub fn register_capture(&mut self, name: &'static str, constructor: Constructor<dyn Capture>) {
+        self.captures.insert(name, constructor);
+    }
+
+    pub fn register_detector(
+        &mut self,
+        name: &'static str,
+        constructor: Constructor<dyn FiducialDetector>,
+    ) {
+        self.detectors.insert(name, constructor);
+    }
+
+    pub fn register_object_detector(
+        &mut self,
+        name: &'static str,
+        constructor: Constructor<dyn ObjectDetector>,
+    ) {
+        self.object_detectors.insert(name, constructor);
+    }
+
+    pub fn register_pose_estimator(
+        &mut self,
+        name: &'static str,
+        constructor: Constructor<dyn CameraPoseEstimator>,
+    ) {
+        self.pose_estimators.insert(name, constructor);
+    }
+
+    pub fn register_output(
+        &mut self,
+        name: &'static str,
+        constructor: Constructor<dyn OutputSink>,
+    ) {
+        self.outputs.insert(name, constructor);
+    }
+}
+
+#[derive(Deserialize)]
+struct V4lOptions {
+    #[serde(default)]
+    device: i32,
+}
+
+#[derive(Deserialize)]
+struct TestOptions {
+    /// Image returned for every frame, the built in test image if not set
+    #[serde(default)]
+    path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ArucoOptions {
+    #[serde(default = "default_family")]
+    family: String,
+}
+
+fn default_family() -> String {
+    "36h11".into()
+}
+
+/// Every stage implementation that ships with watson-vision
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self {
+            captures: HashMap::new(),
+            detectors: HashMap::new(),
+            object_detectors: HashMap::new(),
+            pose_estimators: HashMap::new(),
+            outputs: HashMap::new(),
+        };
+        registry.register_capture("gstreamer", |_, _| {
+            Ok(Box::new(GStreamerCapture::default()))
+        });
+        registry.register_capture("v4l", |config, _| {
+            let options: V4lOptions = options(config)?;
+            Ok(Box::new(DefaultCapture::new(options.device)))
+        });
+        registry.register_capture("test", |config, _| {
+            let options: TestOptions = options(config)?;
+            Ok(match options.path {
+                Some(path) => Box::new(TestCapture::from_file(&path)?),
+                None => Box::new(TestCapture::default()),
+            })
+        });
+        registry.register_detector("aruco", |config, _| {
+            let options: ArucoOptions = options(config)?;
+            let dictionary = match options.family.as_str() {
+                "16h5" => opencv::aruco::DICT_APRILTAG_16h5,
+                "25h9" => opencv::aruco::DICT_APRILTAG_25h9,
+                "36h10" => opencv::aruco::DICT_APRILTAG_36h10,
+                "36h11" => opencv::aruco::DICT_APRILTAG_36h11,
+                family => {
+                    return Err(PipelineError::Config(format!(
+                        "unknown tag family {:?}",
+                        family
+                    )))
+                }
+            };
+            Ok(Box::new(ArucoFiducialDetector::new(dictionary)?))
+        });
+        registry.register_object_detector("hsv", |config, _| {
+            Ok(Box::new(HsvObjectDetector::new(options(config)?)?))
+        });
+        registry.register_object_detector("retroreflective", |config, _| {
+            Ok(Box::new(RetroreflectiveDetector::new(options(config)?)))
+        });
+        registry.register_object_detector("dnn", |config, _| {
+            Ok(Box::new(DnnObjectDetector::new(options(config)?)?))
+        });
+        registry.register_pose_estimator("multi_target", |_, env| {
+            Ok(Box::new(MultiTargetCameraPoseEstimator {
+                robot_heading: env.robot_heading.clone(),
+            }))
+        });
+        registry.register_output("watson", |_, _| Ok(Box::new(WatsonOutput)));
+        registry.register_output("photonvision", |_, _| Ok(Box::new(PhotonVisionOutput)));
+        registry.register_output("limelight", |_, _| Ok(Box::new(LimelightOutput)));
+        registry
+    }
+}
@@ -0,0 +1,185 @@
+use nalgebra::{
+    DMatrix, DVector, Isometry3, Matrix6, Point3, Translation3, UnitQuaternion, Vector3,
+};
+use opencv::{
+    core::Mat,
+    types::{VectorOfPoint2d, VectorOfVec3d},
+};
+
+use crate::types::translation_to_opencv;
+
+/// A camera looking at points with known field positions.
+pub struct View<'a> {
+    pub camera_matrix: &'a Mat,
+    pub distortion_coefficients: &'a Mat,
+    /// Transform from the body being solved for to this camera
+    pub body_to_camera: Isometry3<f64>,
+    /// Field relative
+    pub object_points: Vec<Vector3<f64>>,
+    /// Pixels, in the same order as `object_points`
+    pub image_points: Vec<[f64; 2]>,
+}
+
+impl View<'_> {
+    pub fn residuals(&self, field_to_body: &Isometry3<f64>) -> opencv::Result<Vec<f64>> {
+        let projected = project_points(
+            &(field_to_body * self.body_to_camera),
+            &self.object_points,
+            self.camera_matrix,
+            self.distortion_coefficients,
+        )?;
+        Ok(projected
+            .iter()
+            .zip(&self.image_points)
+            .flat_map(|(projected, observed)| {
+                [projected[0] - observed[0], projected[1] - observed[1]]
+            })
+            .collect())
+    }
+}
+
+/// Result of [`refine_pose`].
+#[derive(Debug, Clone)]
+pub struct Refinement {
+    pub pose: Isometry3<f64>,
+    /// Reprojection error in pixels, see [`reprojection_error`]
+    pub error: f64,
+    /// Covariance of the translation along the field x, y and z axes followed by the rotation about the body's x, y
+    /// and z axes.
+    pub covariance: Matrix6<f64>,
+}
+
+/// Projects field relative points into the image of a camera at `field_to_camera`.
+pub fn project_points(
+    field_to_camera: &Isometry3<f64>,
+    object_points: &[Vector3<f64>],
+    camera_matrix: &Mat,
+    distortion_coefficients: &Mat,
+) -> opencv::Result<Vec<[f64; 2]>> {
+    let camera_to_field = field_to_camera.inverse();
+    let camera_points = object_points
+        .iter()
+        .map(|x| translation_to_opencv((camera_to_field * Point3::from(*x)).coords))
+        .collect::<VectorOfVec3d>();
+    let zero = Mat::from_slice(&[0.0f64; 3])?;
+    let mut image_points = VectorOfPoint2d::new();
+    opencv::calib3d::project_points_def(
+        &camera_points,
+        &zero,
+        &zero,
+        camera_matrix,
+        distortion_coefficients,
+        &mut image_points,
+    )?;
+    Ok(image_points.iter().map(|x| [x.x, x.y]).collect())
+}
+
+/// Root mean square pixel error between `image_points` and the projection of `object_points`. Both coordinates of a
+/// point count separately, like the errors returned by `solvePnPGeneric`.
+pub fn reprojection_error(
+    field_to_camera: &Isometry3<f64>,
+    object_points: &[Vector3<f64>],
+    image_points: &[[f64; 2]],
+    camera_matrix: &Mat,
+    distortion_coefficients: &Mat,
+) -> opencv::Result<f64> {
+    let projected = project_points(
+        field_to_camera,
+        object_points,
+        camera_matrix,
+        distortion_coefficients,
+    )?;
+    let squared_error: f64 = projected
+        .iter()
+        .zip(image_points)
+        .map(|(projected, observed)| {
+            (projected[0] - observed[0]).powi(2) + (projected[1] - observed[1]).powi(2)
+        })
+        .sum();
+    Ok((squared_error / (2 * projected.len()) as f64).sqrt())
+}
+
+fn perturb(pose: &Isometry3<f64>, delta: &DVector<f64>) -> Isometry3<f64> {
+    Isometry3::from_parts(
+        Translation3::from(pose.translation.vector + Vector3::new(delta[0], delta[1], delta[2])),
+        pose.rotation
+            * UnitQuaternion::from_scaled_axis(Vector3::new(delta[3], delta[4], delta[5])),
+    )
+}
+
+fn residuals(views: &[View], field_to_body: &Isometry3<f64>) -> opencv::Result<DVector<f64>> {
+    let mut residuals = Vec::new();
+    for view in views {
+        residuals.extend(view.residuals(field_to_body)?);
+    }
+    Ok(DVector::from_vec(residuals))
+}
+
+fn jacobian(
+    views: &[View],
+    field_to_body: &Isometry3<f64>,
+    residuals_at_pose: &DVector<f64>,
+) -> opencv::Result<DMatrix<f64>> {
+    const STEP: f64 = 1e-6;
+    let mut jacobian = DMatrix::zeros(residuals_at_pose.len(), 6);
+    for i in 0..6 {
+        let mut delta = DVector::zeros(6);
+        delta[i] = STEP;
+        let stepped = residuals(views, &perturb(field_to_body, &delta))?;
+        jacobian.set_column(i, &((stepped - residuals_at_pose) / STEP));
+    }
+    Ok(jacobian)
+}
+
+/// Minimizes the reprojection error of every view over the pose of the body with Levenberg-Marquardt, starting from
+/// `initial`. Returns `None` if there are not enough points to constrain the pose.
+pub fn refine_pose(
+    initial: Isometry3<f64>,
+    views: &[View],
+    max_iterations: usize,
+) -> opencv::Result<Option<Refinement>> {
+    let point_count: usize = views.iter().map(|x| x.object_points.len()).sum();
+    if point_count * 2 <= 6 {
+        return Ok(None);
+    }
+
+    let mut pose = initial;
+    let mut current = residuals(views, &pose)?;
+    let mut cost = current.norm_squared();
+    let mut damping = 1e-3;
+    for _ in 0..max_iterations {
+        let jacobian = jacobian(views, &pose, &current)?;
+        let hessian = jacobian.transpose() * &jacobian;
+        let gradient = jacobian.transpose() * &current;
+        let damped = &hessian + DMatrix::from_diagonal(&hessian.diagonal()) * damping;
+        let Some(step) = damped.cholesky().map(|x| x.solve(&-gradient)) else {
+            break;
+        };
+        let candidate = perturb(&pose, &step);
+        let candidate_residuals = residuals(views, &candidate)?;
+        let candidate_cost = candidate_residuals.norm_squared();
+        if candidate_cost < cost {
+            let converged = cost - candidate_cost < 1e-10 * cost || step.norm() < 1e-10;
+            pose = candidate;
+            current = candidate_residuals;
+            cost = candidate_cost;
+            damping /= 10.0;
+            if converged {
+                break;
+            }
+        } else {
+            damping *= 10.0;
+        }
+    }
+
+    let jacobian = jacobian(views, &pose, &current)?;
+    let Some(information_inverse) = (jacobian.transpose() * &jacobian).try_inverse() else {
+        return Ok(None);
+    };
+    let variance = cost / (current.len() - 6) as f64;
+    Ok(Some(Refinement {
+        pose,
+        error: (cost / current.len() as f64).sqrt(),
+        covariance: Matrix6::from_iterator((information_inverse * variance).iter().copied()),
+    }))
+}
@@ -0,0 +1,481 @@
+use nalgebra::{Isometry3, Vector3};
+use opencv::{
+    core::{Mat, Point, Rect, Vec2d, Vec4f},
+    imgproc,
+    prelude::*,
+    types::{
+        VectorOfMat, VectorOfPoint, VectorOfVec2d, VectorOfVec3d, VectorOfVectorOfPoint,
+        VectorOff64,
+    },
+};
+use serde::Deserialize;
+
+use super::{
+    error::PipelineError,
+    object_detector::{object_targets, ObjectDetector, ObjectTarget},
+};
+use crate::{
+    config::CameraConfig,
+    types::{isometry_from_opencv, translation_to_opencv},
+};
+
+/// How pieces of tape are combined into one target.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Grouping {
+    /// Every piece of tape is a target
+    Single,
+    /// Neighbouring pieces of tape that pass the `intersection` rule, like the 2019 vision targets
+    Pair,
+    /// Runs of evenly spaced pieces of tape, like the 2022 hub
+    Arc,
+}
+
+/// Where the lines along two pieces of tape have to meet for them to be paired.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Intersection {
+    /// Pair any neighbours
+    None,
+    Above,
+    Below,
+    Left,
+    Right,
+}
+
+/// Options of [`RetroreflectiveDetector`]. Meant for a preset with a low `exposure` so only the lit tape is bright.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetroreflectiveOptions {
+    /// 0 to 255, of the green channel, or of the gray image when `green` is off
+    pub min_brightness: f64,
+    /// Also require green to be this much brighter than red and blue, for green LED rings
+    pub green: bool,
+    pub min_green_excess: f64,
+    /// Percent of the image, of each piece of tape
+    pub min_area: f64,
+    pub max_area: f64,
+    /// Contour area over the area of its rotated bounding rectangle
+    pub min_fill: f64,
+    pub grouping: Grouping,
+    pub intersection: Intersection,
+    /// Widest gap between neighbouring pieces of tape of an arc, in tape widths
+    pub max_gap: f64,
+    /// Fewest pieces of tape that make an arc
+    pub min_arc_size: usize,
+    /// Target relative positions in meters of the top left, top right, bottom right and bottom left corners of a
+    /// target, x out of the target, y left and z up. Targets are solved for when set.
+    pub model: Option<[[f64; 3]; 4]>,
+    pub max_targets: usize,
+    /// Stream the thresholded mask instead of the camera image
+    pub stream_mask: bool,
+}
+
+impl Default for RetroreflectiveOptions {
+    fn default() -> Self {
+        Self {
+            min_brightness: 200.0,
+            green: true,
+            min_green_excess: 40.0,
+            min_area: 0.01,
+            max_area: 20.0,
+            min_fill: 0.5,
+            grouping: Grouping::Pair,
+            intersection: Intersection::None,
+            max_gap: 2.0,
+            min_arc_size: 3,
+            model: None,
+            max_targets: 4,
+            stream_mask: false,
+        }
+    }
+}
+
+/// A bright contour that passed the filters
+struct Tape {
+    contour: VectorOfPoint,
+    rect: Rect,
+    area: f64,
+    /// A point on the line fit through the contour, and the direction of the line
+    point: [f64; 2],
+    direction: [f64; 2],
+}
+
+/// Finds retroreflective tape lit by the camera's LEDs and groups it into targets.
+pub struct RetroreflectiveDetector {
+    options: RetroreflectiveOptions,
+    mask: Option<Mat>,
+}
+
+impl RetroreflectiveDetector {
+    pub fn new(options: RetroreflectiveOptions) -> Self {
+        Self {
+            options,
+            mask: None,
+        }
+    }
+
+    fn threshold(&self, image: &Mat) -> opencv::Result<Mat> {
+        let options = &self.options;
+        let mut mask = Mat::default();
+        if !options.green {
+            let mut gray = Mat::default();
+            imgproc::cvt_color(image, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?;
+            imgproc::threshold(
+                &gray,
+                &mut mask,
+                options.min_brightness,
+                255.0,
+                imgproc::THRESH_BINARY,
+            )?;
+            return Ok(mask);
+        }
+        let mut channels = VectorOfMat::new();
+        opencv::core::split(image, &mut channels)?;
+        let (blue, green, red) = (channels.get(0)?, channels.get(1)?, channels.get(2)?);
+        imgproc::threshold(
+            &green,
+            &mut mask,
+            options.min_brightness,
+            255.0,
+            imgproc::THRESH_BINARY,
+        )?;
+        let mut red_blue = Mat::default();
+        opencv::core::max(&red, &blue, &mut red_blue)?;
+        // Saturates at 0 where red or blue is brighter
+        let mut excess = Mat::default();
+        opencv::core::subtract(
+            &green,
+            &red_blue,
+            &mut excess,
+            &opencv::core::no_array(),
+            -1,
+        )?;
+        let mut excess_mask = Mat::default();
+        imgproc::threshold(
+            &excess,
+            &mut excess_mask,
+            options.min_green_excess,
+            255.0,
+            imgproc::THRESH_BINARY,
+        )?;
+        let mut both = Mat::default();
+        opencv::core::bitwise_and(&mask, &excess_mask, &mut both, &opencv::core::no_array())?;
+        Ok(both)
+    }
+
+    fn tapes(&self, mask: &Mat) -> opencv::Result<Vec<Tape>> {
+        let options = &self.options;
+        let mut contours = VectorOfVectorOfPoint::new();
+        imgproc::find_contours(
+            mask,
+            &mut contours,
+            imgproc::RETR_EXTERNAL,
+            imgproc::CHAIN_APPROX_SIMPLE,
+            Point::default(),
+        )?;
+        let image_area = (mask.cols() * mask.rows()) as f64;
+        let mut tapes = Vec::new();
+        for contour in contours {
+            let area = imgproc::contour_area(&contour, false)?;
+            let rotated = imgproc::min_area_rect(&contour)?;
+            let rotated_area = (rotated.size.width * rotated.size.height) as f64;
+            if rotated_area <= 0.0
+                || !(options.min_area..=options.max_area).contains(&(area / image_area * 100.0))
+                || area / rotated_area < options.min_fill
+            {
+                continue;
+            }
+            let mut line = Vec4f::default();
+            imgproc::fit_line(&contour, &mut line, imgproc::DIST_L2, 0.0, 0.01, 0.01)?;
+            let [dx, dy, x, y] = line.0.map(|x| x as f64);
+            tapes.push(Tape {
+                rect: imgproc::bounding_rect(&contour)?,
+                contour,
+                area,
+                point: [x, y],
+                direction: [dx, dy],
+            });
+        }
+        tapes.sort_by_key(|x| x.rect.x);
+        Ok(tapes)
+    }
+
+    /// Whether the lines along `a` and `b` meet where `intersection` asks for
+    fn paired(&self, a: &Tape, b: &Tape) -> bool {
+        let ([ax, ay], [adx, ady]) = (a.point, a.direction);
+        let ([bx, by], [bdx, bdy]) = (b.point, b.direction);
+        let intersection = || {
+            let cross = adx * bdy - ady * bdx;
+            // Parallel lines never meet
+            (cross.abs() >= 1e-6).then(|| {
+                let t = ((bx - ax) * bdy - (by - ay) * bdx) / cross;
+                [ax + t * adx, ay + t * ady]
+            })
+        };
+        // Image y is down
+        match self.options.intersection {
+            Intersection::None => true,
+            Intersection::Above => intersection().is_some_and(|[_, y]| y < ay.min(by)),
+            Intersection::Below => intersection().is_some_and(|[_, y]| y > ay.max(by)),
+            Intersection::Left => intersection().is_some_and(|[x, _]| x < ax.min(bx)),
+            Intersection::Right => intersection().is_some_and(|[x, _]| x > ax.max(bx)),
+        }
+    }
+
+    /// Indices into `tapes`, which are sorted left to right, of the tape of each target
+    fn group(&self, tapes: &[Tape]) -> Vec<Vec<usize>> {
+        let options = &self.options;
+        match options.grouping {
+            Grouping::Single => (0..tapes.len()).map(|i| vec![i]).collect(),
+            Grouping::Pair => {
+                let mut groups = Vec::new();
+                let mut i = 0;
+                while i + 1 < tapes.len() {
+                    if self.paired(&tapes[i], &tapes[i + 1]) {
+                        groups.push(vec![i, i + 1]);
+                        i += 2;
+                    } else {
+                        i += 1;
+                    }
+                }
+                groups
+            }
+            Grouping::Arc => {
+                let mut groups: Vec<Vec<usize>> = Vec::new();
+                for (i, tape) in tapes.iter().enumerate() {
+                    let continues = groups.last().and_then(|x| x.last()).is_some_and(|&last| {
+                        let previous = &tapes[last];
+                        let gap = tape.rect.x - (previous.rect.x + previous.rect.width);
+                        let width = (tape.rect.width + previous.rect.width) as f64 / 2.0;
+                        gap as f64 <= options.max_gap * width
+                    });
+                    match groups.last_mut() {
+                        Some(group) if continues => group.push(i),
+                        _ => groups.push(vec![i]),
+                    }
+                }
+                groups.retain(|x| x.len() >= options.min_arc_size);
+                groups
+            }
+        }
+    }
+}
+
+/// The outermost points of a group of contours in each diagonal direction: top left, top right, bottom right and
+/// bottom left.
+fn group_corners(points: &[Point]) -> [[f64; 2]; 4] {
+    let extreme = |key: fn(&Point) -> i32, max: bool| {
+        let point = if max {
+            points.iter().max_by_key(|x| key(x))
+        } else {
+            points.iter().min_by_key(|x| key(x))
+        }
+        .copied()
+        .unwrap_or_default();
+        [point.x as f64, point.y as f64]
+    };
+    [
+        extreme(|p| p.x + p.y, false),
+        extreme(|p| p.x - p.y, true),
+        extreme(|p| p.x + p.y, true),
+        extreme(|p| p.x - p.y, false),
+    ]
+}
+
+/// Pose of the target relative to the camera from its corners in the image.
+fn solve_target(
+    corners: &[[f64; 2]; 4],
+    model: &[[f64; 3]; 4],
+    config_store: &CameraConfig,
+) -> opencv::Result<Option<Isometry3<f64>>> {
+    let object_points = model
+        .iter()
+        .map(|x| translation_to_opencv(Vector3::from(*x)))
+        .collect::<VectorOfVec3d>();
+    let image_points = corners
+        .iter()
+        .map(|x| Vec2d::from_array(*x))
+        .collect::<VectorOfVec2d>();
+    let mut rvecs = VectorOfVec3d::new();
+    let mut tvecs = VectorOfVec3d::new();
+    let mut errors = VectorOff64::new();
+    opencv::calib3d::solve_pnp_generic(
+        &object_points,
+        &image_points,
+        &config_store.camera_matrix,
+        &config_store.distortion_coefficients,
+        &mut rvecs,
+        &mut tvecs,
+        false,
+        opencv::calib3d::SolvePnPMethod::SOLVEPNP_SQPNP,
+        &opencv::core::no_array(),
+        &opencv::core::no_array(),
+        &mut errors,
+    )?;
+    if tvecs.len() < 1 || rvecs.len() < 1 {
+        return Ok(None);
+    }
+    Ok(Some(isometry_from_opencv(tvecs.get(0)?, rvecs.get(0)?)))
+}
+
+impl ObjectDetector for RetroreflectiveDetector {
+    fn detect_objects(
+        &mut self,
+        image: &Mat,
+        config_store: &CameraConfig,
+    ) -> Result<Vec<ObjectTarget>, PipelineError> {
+        let options = &self.options;
+        let mask = self.threshold(image)?;
+        let tapes = self.tapes(&mask)?;
+
+        let mut groups = self
+            .group(&tapes)
+            .into_iter()
+            .map(|group| {
+                let points = group
+                    .iter()
+                    .flat_map(|&i| tapes[i].contour.iter())
+                    .collect::<Vec<_>>();
+                let rect =
+                    imgproc::bounding_rect(&points.iter().copied().collect::<VectorOfPoint>())?;
+                let area = group.iter().map(|&i| tapes[i].area).sum::<f64>();
+                Ok((rect, area, group_corners(&points)))
+            })
+            .collect::<opencv::Result<Vec<_>>>()?;
+        // Sorted here so the corners still line up with the targets after object_targets sorts them
+        groups.sort_by(|a, b| b.1.total_cmp(&a.1));
+        groups.truncate(options.max_targets);
+
+        let blobs = groups
+            .iter()
+            .map(|&(rect, area, _)| (rect, area, 0, None))
+            .collect();
+        let mut targets = object_targets(blobs, usize::MAX, config_store)?;
+        for (target, (_, _, corners)) in targets.iter_mut().zip(groups) {
+            if let Some(model) = &options.model {
+                target.camera_to_target = solve_target(&corners, model, config_store)?;
+            }
+            target.corners = Some(corners);
+        }
+        self.mask = options.stream_mask.then_some(mask);
+        Ok(targets)
+    }
+
+    fn debug_image(&self) -> Option<&Mat> {
+        self.mask.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A rectangular piece of tape, with its line through the center along `direction`
+    fn tape(x: i32, y: i32, width: i32, height: i32, direction: [f64; 2]) -> Tape {
+        let contour = [
+            Point::new(x, y),
+            Point::new(x + width, y),
+            Point::new(x + width, y + height),
+            Point::new(x, y + height),
+        ]
+        .into_iter()
+        .collect::<VectorOfPoint>();
+        Tape {
+            contour,
+            rect: Rect::new(x, y, width, height),
+            area: (width * height) as f64,
+            point: [
+                x as f64 + width as f64 / 2.0,
+                y as f64 + height as f64 / 2.0,
+            ],
+            direction,
+        }
+    }
+
+    fn detector(grouping: Grouping, intersection: Intersection) -> RetroreflectiveDetector {
+        RetroreflectiveDetector::new(RetroreflectiveOptions {
+            grouping,
+            intersection,
+            ..Default::default()
+        })
+    }
+
+    /// Tilted towards each other at the top like the 2019 targets, so their lines meet above them
+    fn leaning_pair() -> [Tape; 2] {
+        [
+            tape(100, 100, 10, 40, [0.3, -1.0]),
+            tape(140, 100, 10, 40, [-0.3, -1.0]),
+        ]
+    }
+
+    #[test]
+    fn pairs_where_the_lines_meet() {
+        let [a, b] = leaning_pair();
+        assert!(detector(Grouping::Pair, Intersection::Above).paired(&a, &b));
+        assert!(!detector(Grouping::Pair, Intersection::Below).paired(&a, &b));
+        assert!(detector(Grouping::Pair, Intersection::None).paired(&a, &b));
+    }
+
+    #[test]
+    fn parallel_tape_only_pairs_without_an_intersection() {
+        let a = tape(100, 100, 10, 40, [0.0, 1.0]);
+        let b = tape(140, 100, 10, 40, [0.0, 1.0]);
+        assert!(!detector(Grouping::Pair, Intersection::Above).paired(&a, &b));
+        assert!(!detector(Grouping::Pair, Intersection::Left).paired(&a, &b));
+        assert!(detector(Grouping::Pair, Intersection::None).paired(&a, &b));
+    }
+
+    #[test]
+    fn groups_pairs_left_to_right() {
+        let [a, b] = leaning_pair();
+        // Leans the other way so it doesn't pair with either neighbour
+        let stray = tape(60, 100, 10, 40, [-0.3, -1.0]);
+        let [c, d] = leaning_pair().map(|mut x| {
+            x.rect.x += 200;
+            x.point[0] += 200.0;
+            x
+        });
+        let tapes = [stray, a, b, c, d];
+        assert_eq!(
+            detector(Grouping::Pair, Intersection::Above).group(&tapes),
+            vec![vec![1, 2], vec![3, 4]]
+        );
+        assert_eq!(
+            detector(Grouping::Single, Intersection::Above).group(&tapes),
+            (0..5).map(|i| vec![i]).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn groups_evenly_spaced_tape_into_arcs() {
+        let up = [0.0, 1.0];
+        // Gaps of 10 pixels between 10 pixel wide tape, then a gap of 50
+        let tapes = [
+            tape(0, 0, 10, 5, up),
+            tape(20, 0, 10, 5, up),
+            tape(40, 0, 10, 5, up),
+            tape(100, 0, 10, 5, up),
+            tape(120, 0, 10, 5, up),
+        ];
+        let mut detector = detector(Grouping::Arc, Intersection::None);
+        assert_eq!(detector.group(&tapes), vec![vec![0, 1, 2]]);
+        detector.options.min_arc_size = 2;
+        assert_eq!(detector.group(&tapes), vec![vec![0, 1, 2], vec![3, 4]]);
+    }
+
+    #[test]
+    fn group_corners_are_the_outermost_points() {
+        let [a, b] = leaning_pair();
+        let points = a.contour.iter().chain(b.contour.iter()).collect::<Vec<_>>();
+        assert_eq!(
+            group_corners(&points),
+            [
+                [100.0, 100.0],
+                [150.0, 100.0],
+                [150.0, 140.0],
+                [100.0, 140.0]
+            ]
+        );
+    }
+}
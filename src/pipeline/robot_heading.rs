@@ -0,0 +1,109 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use nalgebra::{DMatrix, DVector, Isometry3, Translation3, UnitQuaternion, Vector3};
+use opencv::{
+    core::Vec2d,
+    types::{VectorOfPoint2d, VectorOfVec2d},
+};
+use parking_lot::Mutex;
+
+use crate::config::CameraConfig;
+
+/// Latest gyro heading published by the robot, shared between the NetworkTables thread and the pipelines.
+#[derive(Debug, Clone)]
+pub struct RobotHeading {
+    latest: Arc<Mutex<Option<(f64, Instant)>>>,
+    max_age: Duration,
+}
+
+impl RobotHeading {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            latest: Arc::new(Mutex::new(None)),
+            max_age,
+        }
+    }
+
+    /// Radians, counter-clockwise positive
+    pub fn set(&self, heading: f64) {
+        *self.latest.lock() = Some((heading, Instant::now()));
+    }
+
+    /// Radians, counter-clockwise positive. `None` if the robot has not published a heading recently.
+    pub fn get(&self) -> Option<f64> {
+        let (heading, received) = (*self.latest.lock())?;
+        (received.elapsed() <= self.max_age).then_some(heading)
+    }
+}
+
+/// Difference between the yaw of the robot at `field_to_camera` and `heading`, in radians.
+pub fn heading_error(
+    field_to_camera: &Isometry3<f64>,
+    heading: f64,
+    config_store: &CameraConfig,
+) -> f64 {
+    let (_, _, yaw) = config_store
+        .robot_pose(field_to_camera)
+        .rotation
+        .euler_angles();
+    let error = (yaw - heading).rem_euclid(std::f64::consts::TAU);
+    error.min(std::f64::consts::TAU - error)
+}
+
+/// Solves for only the position of the camera, assuming the robot is flat on the floor and facing `heading`.
+/// `object_points` are field relative.
+pub fn solve_heading_constrained(
+    object_points: &[Vector3<f64>],
+    image_points: &[[f64; 2]],
+    heading: f64,
+    config_store: &CameraConfig,
+) -> opencv::Result<Option<Isometry3<f64>>> {
+    let rotation = UnitQuaternion::from_euler_angles(0.0, 0.0, heading)
+        * config_store
+            .robot_to_camera
+            .map_or(UnitQuaternion::identity(), |x| x.rotation);
+
+    let distorted = image_points
+        .iter()
+        .map(|x| Vec2d::from_array(*x))
+        .collect::<VectorOfVec2d>();
+    let mut normalized = VectorOfPoint2d::new();
+    opencv::calib3d::undistort_points_def(
+        &distorted,
+        &mut normalized,
+        &config_store.camera_matrix,
+        &config_store.distortion_coefficients,
+    )?;
+
+    // In the camera frame (x forward, y left, z up) a point p projects to u = -p.y / p.x and v = -p.z / p.x, so
+    // (-u, -1, 0) . p = 0 and (-v, 0, -1) . p = 0. With p = R^-1 (field_point - t) both are linear in t.
+    let inverse_rotation = rotation.inverse().to_rotation_matrix().into_inner();
+    let mut a = DMatrix::zeros(object_points.len() * 2, 3);
+    let mut b = DVector::zeros(object_points.len() * 2);
+    for (i, (object_point, image_point)) in object_points.iter().zip(normalized.iter()).enumerate()
+    {
+        let camera_point = inverse_rotation * object_point;
+        for (j, row) in [
+            Vector3::new(-image_point.x, -1.0, 0.0),
+            Vector3::new(-image_point.y, 0.0, -1.0),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            a.row_mut(i * 2 + j)
+                .copy_from(&(row.transpose() * inverse_rotation));
+            b[i * 2 + j] = row.dot(&camera_point);
+        }
+    }
+
+    let Ok(translation) = a.svd(true, true).solve(&b, 1e-9) else {
+        return Ok(None);
+    };
+    Ok(Some(Isometry3::from_parts(
+        Translation3::new(translation[0], translation[1], translation[2]),
+        rotation,
+    )))
+}
@@ -0,0 +1,189 @@
+use std::time::{Duration, Instant};
+
+use nalgebra::{Matrix3, Point3, Rotation3};
+use opencv::{
+    core::{Mat, Point, Scalar, Size},
+    imgcodecs, imgproc,
+    prelude::*,
+    types::{VectorOfPoint, VectorOfVectorOfPoint, VectorOfi32, VectorOfu8},
+};
+
+use super::{
+    camera_pose_estimator::fiducial_corners, object_detector::draw_objects, output::FrameResult,
+    reprojection::project_points,
+};
+use crate::{config::StreamConfig, metrics::METRICS, millis_between};
+
+const TAG_COLOR: (f64, f64, f64) = (0.0, 255.0, 0.0);
+const LAYOUT_COLOR: (f64, f64, f64) = (255.0, 160.0, 0.0);
+
+fn scalar((b, g, r): (f64, f64, f64)) -> Scalar {
+    Scalar::new(b, g, r, 0.0)
+}
+
+/// Takes the camera frame from ours to OpenCV camera coordinates, x right, y down and z forward.
+fn opencv_from_camera() -> Matrix3<f64> {
+    Matrix3::new(0.0, -1.0, 0.0, 0.0, 0.0, -1.0, 1.0, 0.0, 0.0)
+}
+
+fn draw_outline(image: &mut Mat, corners: &[[f64; 2]], color: Scalar) -> opencv::Result<()> {
+    let outline = corners
+        .iter()
+        .map(|[x, y]| Point::new(*x as i32, *y as i32))
+        .collect::<VectorOfPoint>();
+    imgproc::polylines(
+        image,
+        &VectorOfVectorOfPoint::from_iter([outline]),
+        true,
+        color,
+        2,
+        imgproc::LINE_8,
+        0,
+    )
+}
+
+fn draw_text(image: &mut Mat, text: &str, origin: Point, color: Scalar) -> opencv::Result<()> {
+    // Dark outline so the text is readable on any background
+    for (color, thickness) in [(Scalar::default(), 4), (color, 2)] {
+        imgproc::put_text(
+            image,
+            text,
+            origin,
+            imgproc::FONT_HERSHEY_SIMPLEX,
+            0.7,
+            color,
+            thickness,
+            imgproc::LINE_AA,
+            false,
+        )?;
+    }
+    Ok(())
+}
+
+/// Draws what the pipeline found in `frame` onto `image`: the outline and ID of each tag, objects, the axes of each
+/// tag the pose was solved from, the layout tags reprojected with the pose, and the frame rate and latency.
+pub fn annotate(image: &mut Mat, frame: &FrameResult, fps: f64) -> opencv::Result<()> {
+    let config = frame.config;
+    for observation in frame.observations {
+        draw_outline(image, &observation.corners, scalar(TAG_COLOR))?;
+        let [x, y] = observation.corners[0];
+        draw_text(
+            image,
+            &observation.tag_id.to_string(),
+            Point::new(x as i32, y as i32 - 8),
+            scalar(TAG_COLOR),
+        )?;
+    }
+    if let Some(objects) = frame.objects {
+        draw_objects(image, objects)?;
+    }
+
+    if let Some(pose) = frame.pose {
+        let camera_to_field = pose.pose_0.inverse();
+        for tag in &config.tag_layout.tags {
+            let corners = fiducial_corners(tag.pose, config.fiducial_size_m);
+            // Projecting points behind the camera would draw them mirrored
+            if corners
+                .iter()
+                .any(|x| (camera_to_field * Point3::from(*x)).x <= 0.0)
+            {
+                continue;
+            }
+            let projected = project_points(
+                &pose.pose_0,
+                &corners,
+                &config.camera_matrix,
+                &config.distortion_coefficients,
+            )?;
+            draw_outline(image, &projected, scalar(LAYOUT_COLOR))?;
+
+            if pose.tag_ids.contains(&tag.id) {
+                let camera_to_tag = camera_to_field * tag.pose;
+                let rotation = Rotation3::from_matrix_unchecked(
+                    opencv_from_camera() * camera_to_tag.rotation.to_rotation_matrix().matrix(),
+                );
+                let translation = opencv_from_camera() * camera_to_tag.translation.vector;
+                let rvec = Mat::from_slice(rotation.scaled_axis().as_slice())?;
+                let tvec = Mat::from_slice(translation.as_slice())?;
+                opencv::calib3d::draw_frame_axes(
+                    image,
+                    &config.camera_matrix,
+                    &config.distortion_coefficients,
+                    &rvec,
+                    &tvec,
+                    (config.fiducial_size_m / 2.0) as f32,
+                    2,
+                )?;
+            }
+        }
+    }
+
+    draw_text(
+        image,
+        &format!(
+            "{:.1} fps  {:.1} ms",
+            fps,
+            millis_between(frame.captured, Instant::now())
+        ),
+        Point::new(10, 30),
+        Scalar::all(255.0),
+    )
+}
+
+/// Encodes frames for the MJPEG stream of one camera.
+#[derive(Default)]
+pub struct StreamEncoder {
+    last_frame: Option<Instant>,
+}
+
+impl StreamEncoder {
+    /// Whether the next frame should be streamed, which is when there are clients and the frame rate allows it
+    pub fn wants_frame(&self, config: &StreamConfig, camera_name: &str) -> bool {
+        if config.only_with_clients
+            && METRICS
+                .mjpeg_clients
+                .with_label_values(&[camera_name])
+                .get()
+                <= 0
+        {
+            return false;
+        }
+        match (config.max_fps, self.last_frame) {
+            (Some(max_fps), Some(last_frame)) if max_fps > 0.0 => {
+                last_frame.elapsed() >= Duration::from_secs_f64(1.0 / max_fps)
+            }
+            _ => true,
+        }
+    }
+
+    /// `image` scaled and encoded as one part of a `multipart/x-mixed-replace` response
+    pub fn encode(&mut self, image: &Mat, config: &StreamConfig) -> opencv::Result<Vec<u8>> {
+        self.last_frame = Some(Instant::now());
+        let (cols, rows) = (image.cols(), image.rows());
+        let size = match (config.width, config.height) {
+            (Some(width), Some(height)) => Some(Size::new(width, height)),
+            (Some(width), None) => Some(Size::new(width, rows * width / cols.max(1))),
+            (None, Some(height)) => Some(Size::new(cols * height / rows.max(1), height)),
+            (None, None) => None,
+        };
+        let resized;
+        let image = match size {
+            Some(size) if size.width > 0 && size.height > 0 && size != Size::new(cols, rows) => {
+                let mut scaled = Mat::default();
+                imgproc::resize(image, &mut scaled, size, 0.0, 0.0, imgproc::INTER_AREA)?;
+                resized = scaled;
+                &resized
+            }
+            _ => image,
+        };
+
+        let mut data = VectorOfu8::new();
+        let params = VectorOfi32::from_iter([imgcodecs::IMWRITE_JPEG_QUALITY, config.quality]);
+        imgcodecs::imencode(".jpg", image, &mut data, &params)?;
+        Ok((*b"--FRAME\r\nContent-Type: image/jpeg\r\n\r\n")
+            .into_iter()
+            .chain(data)
+            .chain(*b"\r\n")
+            .collect())
+    }
+}
@@ -0,0 +1,128 @@
+use nalgebra::{Isometry3, Vector3};
+use opencv::{core::Point2d, types::VectorOfPoint2d};
+
+use crate::{config::CameraConfig, types::FiducialImageObservation};
+
+/// Where a tag is in the image, for aiming at it without a field pose.
+#[derive(Debug, Clone)]
+pub struct TagTarget {
+    pub tag_id: u64,
+    /// Degrees from the optical axis to the center of the tag, positive right
+    pub yaw: f64,
+    /// Degrees from the optical axis to the center of the tag, positive up
+    pub pitch: f64,
+    /// Percent of the image covered by the tag
+    pub area: f64,
+    /// Degrees the top edge of the tag is rotated from horizontal, positive clockwise in the image
+    pub skew: f64,
+}
+
+impl TagTarget {
+    /// Layout of the double arrays published to NetworkTables
+    pub fn to_array(&self) -> [f64; 4] {
+        [self.yaw, self.pitch, self.area, self.skew]
+    }
+}
+
+/// Intersection of the diagonals of the tag, which unlike the mean of the corners is the projection of its center.
+fn center(corners: &[[f64; 2]; 4]) -> [f64; 2] {
+    let [a, b, c, d] = *corners;
+    let (r, s) = ([c[0] - a[0], c[1] - a[1]], [d[0] - b[0], d[1] - b[1]]);
+    let cross = r[0] * s[1] - r[1] * s[0];
+    if cross.abs() < f64::EPSILON {
+        return [
+            corners.iter().map(|x| x[0]).sum::<f64>() / 4.0,
+            corners.iter().map(|x| x[1]).sum::<f64>() / 4.0,
+        ];
+    }
+    let t = ((b[0] - a[0]) * s[1] - (b[1] - a[1]) * s[0]) / cross;
+    [a[0] + t * r[0], a[1] + t * r[1]]
+}
+
+/// Undistorted points on the normalized image plane, one meter in front of the camera, of each pixel.
+pub fn normalize_pixels(
+    pixels: impl IntoIterator<Item = [f64; 2]>,
+    config_store: &CameraConfig,
+) -> opencv::Result<Vec<[f64; 2]>> {
+    let pixels = pixels
+        .into_iter()
+        .map(|[u, v]| Point2d::new(u, v))
+        .collect::<VectorOfPoint2d>();
+    if pixels.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut normalized = VectorOfPoint2d::new();
+    opencv::calib3d::undistort_points_def(
+        &pixels,
+        &mut normalized,
+        &config_store.camera_matrix,
+        &config_store.distortion_coefficients,
+    )?;
+    Ok(normalized.into_iter().map(|x| [x.x, x.y]).collect())
+}
+
+/// Degrees from the optical axis to a point on the normalized image plane, positive right and positive up.
+pub fn yaw_pitch([x, y]: [f64; 2]) -> (f64, f64) {
+    (
+        x.atan().to_degrees(),
+        -y.atan2((1.0 + x.powi(2)).sqrt()).to_degrees(),
+    )
+}
+
+/// Robot relative x and y in meters where the ray through a point on the normalized image plane meets the horizontal
+/// plane `height` meters above the floor. The robot origin is taken to be on the floor. `None` when the ray points
+/// away from the plane, for example for targets above the horizon of a camera looking for pieces on the floor.
+pub fn ground_point(
+    [x, y]: [f64; 2],
+    robot_to_camera: &Isometry3<f64>,
+    height: f64,
+) -> Option<[f64; 2]> {
+    let origin = robot_to_camera.translation.vector;
+    // The OpenCV camera frame has x right, y down and z forward
+    let direction = robot_to_camera.rotation * Vector3::new(1.0, -x, -y);
+    let distance = (height - origin.z) / direction.z;
+    if !distance.is_finite() || distance <= 0.0 {
+        return None;
+    }
+    let point = origin + direction * distance;
+    Some([point.x, point.y])
+}
+
+/// Computes the targeting data of every observed tag.
+pub fn tag_targets(
+    observations: &[FiducialImageObservation],
+    config_store: &CameraConfig,
+) -> opencv::Result<Vec<TagTarget>> {
+    let normalized = normalize_pixels(
+        observations.iter().map(|x| center(&x.corners)),
+        config_store,
+    )?;
+
+    let image_area = config_store.width as f64 * config_store.height as f64;
+    Ok(observations
+        .iter()
+        .zip(normalized)
+        .map(|(observation, center)| {
+            let corners = &observation.corners;
+            // Shoelace formula
+            let area = (0..4)
+                .map(|i| {
+                    let (a, b) = (corners[i], corners[(i + 1) % 4]);
+                    a[0] * b[1] - b[0] * a[1]
+                })
+                .sum::<f64>()
+                .abs()
+                / 2.0;
+            let (yaw, pitch) = yaw_pitch(center);
+            TagTarget {
+                tag_id: observation.tag_id,
+                yaw,
+                pitch,
+                area: area / image_area * 100.0,
+                skew: (corners[1][1] - corners[0][1])
+                    .atan2(corners[1][0] - corners[0][0])
+                    .to_degrees(),
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,464 @@
+//! Binary format of the poses published to `/watson/<camera>`.
+//!
+//! Everything is big-endian. A packet is a header, the pose, then the optional sections named by the flags in the
+//! order listed. Decoders must reject magics they do not know and versions newer than their own. Sections added in
+//! later versions only ever go after the existing ones, behind a new flag.
+//!
+//! | Bytes       | Type         | Field                                                                     |
+//! |-------------|--------------|---------------------------------------------------------------------------|
+//! | 4           | `[u8; 4]`    | Magic, `WVPP`                                                             |
+//! | 1           | `u8`         | Version, currently 1                                                      |
+//! | 1           | `u8`         | Flags, see [`flags`]                                                      |
+//! | 8           | `u64`        | NetworkTables server time of the capture in microseconds                  |
+//! | 4           | `u32`        | Tag count `n`                                                             |
+//! | 8n          | `u64`        | IDs of the tags used in the solve                                         |
+//! | 56          | 7 × `f64`    | Field to camera pose: translation x, y, z then quaternion w, x, y, z      |
+//! | 8           | `f64`        | Reprojection error of the pose in pixels                                  |
+//! | 1           | `u8`         | Why the pose is implausible, 0 if it is not, see `PoseRejection::code`    |
+//! | *ALTERNATE* |              |                                                                           |
+//! | 64          | 8 × `f64`    | Second candidate of an ambiguous single tag solve and its error           |
+//! | *STD_DEVS*  |              |                                                                           |
+//! | 48          | 6 × `f64`    | Standard deviations of x, y, z in meters and roll, pitch, yaw in radians  |
+//! | *PER_TAG*   |              |                                                                           |
+//! | 8n          | `f64`        | Reprojection error of each tag, in the order of the IDs                   |
+//! | 4           | `u32`        | Rejected tag count `m`                                                    |
+//! | 8m          | `u64`        | IDs of tags that were seen but rejected as outliers                       |
+//! | *LATENCY*   |              |                                                                           |
+//! | 8           | `f64`        | Milliseconds from the frame being captured to the packet being published  |
+//! | *COVARIANCE*|              |                                                                           |
+//! | 288         | 36 × `f64`   | Covariance of x, y, z and the rotation about the x, y, z axes, row-major  |
+
+use binrw::{BinRead, BinResult, BinWrite, Endian};
+use nalgebra::{Isometry3, Matrix6, Quaternion, Translation3, UnitQuaternion};
+
+use crate::{pipeline::error::PipelineError, types::CameraPoseObservation};
+
+pub const MAGIC: [u8; 4] = *b"WVPP";
+pub const VERSION: u8 = 1;
+
+/// Bits of the flags byte, each marks an optional section as present
+pub mod flags {
+    pub const ALTERNATE: u8 = 1 << 0;
+    pub const STD_DEVS: u8 = 1 << 1;
+    pub const PER_TAG: u8 = 1 << 2;
+    pub const LATENCY: u8 = 1 << 3;
+    pub const COVARIANCE: u8 = 1 << 4;
+}
+
+/// Per tag section of a [`PosePacket`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PerTag {
+    pub residuals: Vec<f64>,
+    pub rejected_tag_ids: Vec<u64>,
+}
+
+/// A pose as it is sent to the robot, see the [module documentation](self) for the layout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PosePacket {
+    /// Microseconds
+    pub time: u64,
+    pub tag_ids: Vec<u64>,
+    pub pose: Isometry3<f64>,
+    pub error: f64,
+    pub rejection_code: u8,
+    pub alternate: Option<(Isometry3<f64>, f64)>,
+    pub std_devs: Option<[f64; 6]>,
+    pub per_tag: Option<PerTag>,
+    pub latency_ms: Option<f64>,
+    /// Only there when the pose was refined, see [`crate::pipeline::reprojection::Refinement`]
+    pub covariance: Option<Matrix6<f64>>,
+}
+
+impl PosePacket {
+    /// Fails if `observation` does not have a residual for each of its tags, as the residuals are not counted.
+    pub fn new(
+        observation: &CameraPoseObservation,
+        time: u32,
+        latency_ms: Option<f64>,
+    ) -> Result<Self, PipelineError> {
+        if observation.tag_residuals.len() != observation.tag_ids.len() {
+            return Err(PipelineError::Encode(format!(
+                "pose with {} residuals for {} tags",
+                observation.tag_residuals.len(),
+                observation.tag_ids.len()
+            )));
+        }
+        Ok(Self {
+            time: time as u64,
+            tag_ids: observation.tag_ids.clone(),
+            pose: observation.pose_0,
+            error: observation.error_0,
+            rejection_code: observation.rejection.map_or(0, |x| x.code()),
+            alternate: observation.pose_1.zip(observation.error_1),
+            std_devs: Some(observation.std_devs),
+            per_tag: Some(PerTag {
+                residuals: observation.tag_residuals.clone(),
+                rejected_tag_ids: observation.rejected_tag_ids.clone(),
+            }),
+            latency_ms,
+            covariance: observation.covariance,
+        })
+    }
+
+    pub fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.alternate.is_some() {
+            flags |= flags::ALTERNATE;
+        }
+        if self.std_devs.is_some() {
+            flags |= flags::STD_DEVS;
+        }
+        if self.per_tag.is_some() {
+            flags |= flags::PER_TAG;
+        }
+        if self.latency_ms.is_some() {
+            flags |= flags::LATENCY;
+        }
+        if self.covariance.is_some() {
+            flags |= flags::COVARIANCE;
+        }
+        flags
+    }
+
+    /// Encodes the packet for publishing
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut io = std::io::Cursor::new(Vec::with_capacity(256));
+        self.write_be(&mut io).unwrap();
+        io.into_inner()
+    }
+}
+
+fn write_isometry<W: std::io::Write + std::io::Seek>(
+    pose: &Isometry3<f64>,
+    writer: &mut W,
+) -> BinResult<()> {
+    pose.translation.x.write_be(writer)?;
+    pose.translation.y.write_be(writer)?;
+    pose.translation.z.write_be(writer)?;
+    pose.rotation.w.write_be(writer)?;
+    pose.rotation.vector().x.write_be(writer)?;
+    pose.rotation.vector().y.write_be(writer)?;
+    pose.rotation.vector().z.write_be(writer)?;
+    Ok(())
+}
+
+fn read_isometry<R: std::io::Read + std::io::Seek>(reader: &mut R) -> BinResult<Isometry3<f64>> {
+    let [x, y, z, qw, qx, qy, qz] = <[f64; 7]>::read_be(reader)?;
+    Ok(Isometry3::from_parts(
+        Translation3::new(x, y, z),
+        UnitQuaternion::new_normalize(Quaternion::new(qw, qx, qy, qz)),
+    ))
+}
+
+fn write_ids<W: std::io::Write + std::io::Seek>(ids: &[u64], writer: &mut W) -> BinResult<()> {
+    (ids.len() as u32).write_be(writer)?;
+    for id in ids {
+        id.write_be(writer)?;
+    }
+    Ok(())
+}
+
+fn read_ids<R: std::io::Read + std::io::Seek>(reader: &mut R) -> BinResult<Vec<u64>> {
+    let count = u32::read_be(reader)?;
+    (0..count).map(|_| u64::read_be(reader)).collect()
+}
+
+impl BinWrite for PosePacket {
+    type Args<'a> = ();
+
+    fn write_options<W: std::io::prelude::Write + std::io::prelude::Seek>(
+        &self,
+        writer: &mut W,
+        _endian: Endian,
+        _args: Self::Args<'_>,
+    ) -> BinResult<()> {
+        MAGIC.write_be(writer)?;
+        VERSION.write_be(writer)?;
+        self.flags().write_be(writer)?;
+        self.time.write_be(writer)?;
+        write_ids(&self.tag_ids, writer)?;
+        write_isometry(&self.pose, writer)?;
+        self.error.write_be(writer)?;
+        self.rejection_code.write_be(writer)?;
+
+        if let Some((pose, error)) = &self.alternate {
+            write_isometry(pose, writer)?;
+            error.write_be(writer)?;
+        }
+        if let Some(std_devs) = &self.std_devs {
+            std_devs.write_be(writer)?;
+        }
+        if let Some(per_tag) = &self.per_tag {
+            per_tag.residuals.write_be(writer)?;
+            write_ids(&per_tag.rejected_tag_ids, writer)?;
+        }
+        if let Some(latency_ms) = &self.latency_ms {
+            latency_ms.write_be(writer)?;
+        }
+        if let Some(covariance) = &self.covariance {
+            for row in covariance.row_iter() {
+                for value in row.iter() {
+                    value.write_be(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BinRead for PosePacket {
+    type Args<'a> = ();
+
+    fn read_options<R: std::io::prelude::Read + std::io::prelude::Seek>(
+        reader: &mut R,
+        _endian: Endian,
+        _args: Self::Args<'_>,
+    ) -> BinResult<Self> {
+        let pos = reader.stream_position()?;
+        let magic = <[u8; 4]>::read_be(reader)?;
+        if magic != MAGIC {
+            return Err(binrw::Error::BadMagic {
+                pos,
+                found: Box::new(magic),
+            });
+        }
+        let version = u8::read_be(reader)?;
+        if version > VERSION {
+            return Err(binrw::Error::AssertFail {
+                pos: pos + 4,
+                message: format!("unsupported pose packet version {}", version),
+            });
+        }
+        let flags = u8::read_be(reader)?;
+        let time = u64::read_be(reader)?;
+        let tag_ids = read_ids(reader)?;
+        let pose = read_isometry(reader)?;
+        let error = f64::read_be(reader)?;
+        let rejection_code = u8::read_be(reader)?;
+
+        let alternate = if flags & flags::ALTERNATE != 0 {
+            Some((read_isometry(reader)?, f64::read_be(reader)?))
+        } else {
+            None
+        };
+        let std_devs = if flags & flags::STD_DEVS != 0 {
+            Some(<[f64; 6]>::read_be(reader)?)
+        } else {
+            None
+        };
+        let per_tag = if flags & flags::PER_TAG != 0 {
+            Some(PerTag {
+                residuals: (0..tag_ids.len())
+                    .map(|_| f64::read_be(reader))
+                    .collect::<BinResult<_>>()?,
+                rejected_tag_ids: read_ids(reader)?,
+            })
+        } else {
+            None
+        };
+        let latency_ms = if flags & flags::LATENCY != 0 {
+            Some(f64::read_be(reader)?)
+        } else {
+            None
+        };
+        let covariance = if flags & flags::COVARIANCE != 0 {
+            Some(Matrix6::from_row_slice(&<[f64; 36]>::read_be(reader)?))
+        } else {
+            None
+        };
+        Ok(Self {
+            time,
+            tag_ids,
+            pose,
+            error,
+            rejection_code,
+            alternate,
+            std_devs,
+            per_tag,
+            latency_ms,
+            covariance,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: [u8; 5] = [0x57, 0x56, 0x50, 0x50, 0x01];
+
+    const BODY: [u8; 93] = [
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x42, 0x40, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x3f, 0xf0,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3f,
+        0xe0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3f, 0xf0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3f, 0xe0, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00,
+    ];
+
+    const ALTERNATE: [u8; 64] = [
+        0x3f, 0xf0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x3f, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3f, 0xf0, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3f, 0xf8, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const STD_DEVS: [u8; 48] = [
+        0x3f, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3f, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x3f, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3f, 0xf0, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x3f, 0xf0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00,
+    ];
+
+    const PER_TAG: [u8; 28] = [
+        0x3f, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3f, 0xe8, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
+    ];
+
+    const LATENCY: [u8; 8] = [0x40, 0x29, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+    const COVARIANCE: [u8; 288] = [
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3f, 0xf0, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x08, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x40, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x14, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x40, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x1c, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x40, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x22, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x24, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x26,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40,
+        0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x2c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x40, 0x2e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x40, 0x31, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x32, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x40, 0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x34, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x40, 0x35, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x36, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x40, 0x37, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x38, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x39, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x3a,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x3b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40,
+        0x3c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x3d, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x40, 0x3e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x3f, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x40, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x40, 0x80, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x40, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x41, 0x80, 0x00, 0x00,
+        0x00, 0x00, 0x00,
+    ];
+
+    fn pose(x: f64, y: f64, z: f64) -> Isometry3<f64> {
+        Isometry3::translation(x, y, z)
+    }
+
+    fn packet(flags: u8) -> PosePacket {
+        PosePacket {
+            time: 1_000_000,
+            tag_ids: vec![1, 7],
+            pose: pose(1.0, 2.0, 0.5),
+            error: 0.5,
+            rejection_code: 0,
+            alternate: (flags & flags::ALTERNATE != 0).then(|| (pose(1.0, -2.0, 0.5), 1.5)),
+            std_devs: (flags & flags::STD_DEVS != 0).then_some([0.25, 0.25, 0.5, 1.0, 1.0, 2.0]),
+            per_tag: (flags & flags::PER_TAG != 0).then(|| PerTag {
+                residuals: vec![0.5, 0.75],
+                rejected_tag_ids: vec![3],
+            }),
+            latency_ms: (flags & flags::LATENCY != 0).then_some(12.5),
+            covariance: (flags & flags::COVARIANCE != 0)
+                .then(|| Matrix6::from_fn(|row, column| (row * 6 + column) as f64)),
+        }
+    }
+
+    fn expected(flags: u8) -> Vec<u8> {
+        let mut bytes = [&HEADER[..], &[flags], &BODY].concat();
+        for (flag, section) in [
+            (flags::ALTERNATE, &ALTERNATE[..]),
+            (flags::STD_DEVS, &STD_DEVS[..]),
+            (flags::PER_TAG, &PER_TAG[..]),
+            (flags::LATENCY, &LATENCY[..]),
+            (flags::COVARIANCE, &COVARIANCE[..]),
+        ] {
+            if flags & flag != 0 {
+                bytes.extend_from_slice(section);
+            }
+        }
+        bytes
+    }
+
+    fn round_trip(flags: u8) {
+        let packet = packet(flags);
+        assert_eq!(packet.flags(), flags);
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes, expected(flags));
+        let decoded = PosePacket::read_be(&mut std::io::Cursor::new(&bytes)).unwrap();
+        assert_eq!(decoded, packet);
+    }
+
+    #[test]
+    fn no_sections() {
+        round_trip(0);
+    }
+
+    #[test]
+    fn alternate() {
+        round_trip(flags::ALTERNATE);
+    }
+
+    #[test]
+    fn std_devs() {
+        round_trip(flags::STD_DEVS);
+    }
+
+    #[test]
+    fn per_tag() {
+        round_trip(flags::PER_TAG);
+    }
+
+    #[test]
+    fn latency() {
+        round_trip(flags::LATENCY);
+    }
+
+    #[test]
+    fn covariance() {
+        round_trip(flags::COVARIANCE);
+    }
+
+    #[test]
+    fn every_section() {
+        round_trip(
+            flags::ALTERNATE
+                | flags::STD_DEVS
+                | flags::PER_TAG
+                | flags::LATENCY
+                | flags::COVARIANCE,
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_magic() {
+        let mut bytes = expected(0);
+        bytes[0] = b'X';
+        assert!(PosePacket::read_be(&mut std::io::Cursor::new(&bytes)).is_err());
+    }
+
+    #[test]
+    fn rejects_newer_version() {
+        let mut bytes = expected(0);
+        bytes[4] = VERSION + 1;
+        assert!(PosePacket::read_be(&mut std::io::Cursor::new(&bytes)).is_err());
+    }
+
+    #[test]
+    fn residual_per_tag() {
+        let observation = CameraPoseObservation {
+            tag_ids: vec![1, 7],
+            pose_0: Isometry3::identity(),
+            error_0: 0.5,
+            pose_1: None,
+            error_1: None,
+            tag_residuals: vec![0.5],
+            rejected_tag_ids: Vec::new(),
+            covariance: None,
+            std_devs: [0.0; 6],
+            rejection: None,
+        };
+        assert!(PosePacket::new(&observation, 0, None).is_err());
+    }
+}
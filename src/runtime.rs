@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{Receiver, Sender};
+use parking_lot::Mutex;
+
+use crate::{
+    apriltag_thread,
+    config::Config,
+    fusion_thread,
+    metrics::METRICS,
+    nt_thread,
+    pipeline::{
+        error::{PipelineError, Recovery},
+        registry::Registry,
+        robot_heading::RobotHeading,
+    },
+    snapshot::Snapshots,
+    NtConnectError,
+};
+
+/// Consecutive dropped frames after which the capture session is assumed to be dead
+const MAX_DROPPED_FRAMES: u32 = 10;
+/// Wait after a dropped frame, so a capture that fails straight away does not spin
+const DROPPED_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+/// NetworkTables server time, kept up to date by the NetworkTables task and read by every stage to timestamp frames.
+#[derive(Debug, Clone)]
+pub struct NtClock(Arc<Mutex<(u32, Instant)>>);
+
+impl Default for NtClock {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new((0, Instant::now()))))
+    }
+}
+
+impl NtClock {
+    pub fn sync(&self, server_time: u32) {
+        *self.0.lock() = (server_time, Instant::now());
+    }
+
+    /// Microseconds
+    pub fn now(&self) -> u32 {
+        let (server_time, instant) = *self.0.lock();
+        (instant.elapsed().as_micros() as u32).wrapping_add(server_time)
+    }
+}
+
+/// Errors of a single stage since the runtime was created.
+#[derive(Debug, Clone)]
+struct StageStatus {
+    errors: u64,
+    last_error: String,
+    last_error_at: Instant,
+}
+
+/// Collects the errors of every stage of the pipeline, reports them and decides how stages recover.
+#[derive(Debug, Clone, Default)]
+pub struct Supervisor {
+    stages: Arc<Mutex<HashMap<String, StageStatus>>>,
+}
+
+impl Supervisor {
+    /// `stage` is the camera name for camera pipelines, `fusion` or `networktables`
+    pub fn report(&self, stage: &str, error: impl std::fmt::Display) {
+        eprintln!("Error in {}: {}", stage, error);
+        self.record(stage, error);
+    }
+
+    /// Like [`Self::report`] without logging, for errors that are expected every now and then
+    fn record(&self, stage: &str, error: impl std::fmt::Display) {
+        METRICS.stage_errors.with_label_values(&[stage]).inc();
+        let mut stages = self.stages.lock();
+        let status = stages.entry(stage.to_string()).or_insert(StageStatus {
+            errors: 0,
+            last_error: String::new(),
+            last_error_at: Instant::now(),
+        });
+        status.errors += 1;
+        status.last_error = error.to_string();
+        status.last_error_at = Instant::now();
+    }
+
+    /// Records `error` and picks how the stage should carry on. `attempts` is the number of frames in a row that
+    /// failed, including this one.
+    pub fn recover(&self, stage: &str, error: &PipelineError, attempts: u32) -> Recovery {
+        // 100ms doubling up to 3.2s
+        let backoff = Duration::from_millis(100 << attempts.saturating_sub(1).min(5));
+        let recovery = match error {
+            _ if !error.is_recoverable() => Recovery::Stop,
+            PipelineError::DroppedFrame if attempts < MAX_DROPPED_FRAMES => {
+                Recovery::Backoff(DROPPED_FRAME_DELAY)
+            }
+            PipelineError::DroppedFrame | PipelineError::Disconnected(_) => {
+                Recovery::Reopen(backoff)
+            }
+            _ => Recovery::Backoff(backoff),
+        };
+        if matches!(
+            (error, recovery),
+            (PipelineError::DroppedFrame, Recovery::Backoff(_))
+        ) {
+            self.record(stage, error);
+        } else {
+            self.report(stage, error);
+        }
+        recovery
+    }
+
+    /// Error counts and the last error of every stage that had one, served on `/status`
+    pub fn status(&self) -> serde_json::Value {
+        let stages = self.stages.lock();
+        serde_json::Value::Object(
+            stages
+                .iter()
+                .map(|(stage, status)| {
+                    (
+                        stage.clone(),
+                        serde_json::json!({
+                            "errors": status.errors,
+                            "last_error": status.last_error,
+                            "seconds_since_last_error": status.last_error_at.elapsed().as_secs_f64(),
+                        }),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Which pipeline configuration each camera runs, see [`crate::config::CameraConfig::presets`]. Requested by the
+/// robot over NetworkTables or over HTTP and picked up by the camera pipeline on its next frame.
+#[derive(Debug)]
+pub struct PresetSelection {
+    /// Names of the pipelines of each camera
+    names: Vec<Vec<String>>,
+    requested: Vec<AtomicUsize>,
+    active: Vec<AtomicUsize>,
+}
+
+impl PresetSelection {
+    fn new(config: &Config) -> Self {
+        Self {
+            names: config
+                .cameras
+                .iter()
+                .map(|x| x.pipelines().map(|x| x.name.clone()).collect())
+                .collect(),
+            requested: config.cameras.iter().map(|_| AtomicUsize::new(0)).collect(),
+            active: config.cameras.iter().map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+
+    pub fn names(&self, camera: usize) -> &[String] {
+        &self.names[camera]
+    }
+
+    /// Returns false if the camera has no pipeline with that index
+    pub fn request(&self, camera: usize, index: usize) -> bool {
+        if index >= self.names[camera].len() {
+            return false;
+        }
+        self.requested[camera].store(index, Ordering::Relaxed);
+        true
+    }
+
+    pub fn requested(&self, camera: usize) -> usize {
+        self.requested[camera].load(Ordering::Relaxed)
+    }
+
+    /// The pipeline the camera is running
+    pub fn active(&self, camera: usize) -> usize {
+        self.active[camera].load(Ordering::Relaxed)
+    }
+
+    pub fn set_active(&self, camera: usize, index: usize) {
+        self.active[camera].store(index, Ordering::Relaxed);
+    }
+}
+
+/// What every stage gets from the runtime.
+#[derive(Debug, Clone)]
+pub struct StageContext {
+    pub config_content: String,
+    stop: Arc<AtomicBool>,
+    pub clock: NtClock,
+    pub supervisor: Supervisor,
+    pub robot_heading: Option<RobotHeading>,
+    /// Builds the stages each camera's config names
+    pub registry: Arc<Registry>,
+    pub presets: Arc<PresetSelection>,
+    pub snapshots: Arc<Snapshots>,
+}
+
+impl StageContext {
+    /// Stages return as soon as they see this
+    pub fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+}
+
+/// A started pipeline.
+struct Running {
+    context: StageContext,
+    threads: Vec<JoinHandle<()>>,
+    nt_task: tokio::task::JoinHandle<()>,
+}
+
+/// Owns the camera pipelines, the fusion thread and the NetworkTables task, and the channels between them.
+pub struct PipelineRuntime {
+    config_content: String,
+    camera_names: Vec<String>,
+    /// Frames of each camera for the MJPEG streams. These outlive restarts so connected viewers keep working.
+    streams: Vec<(Sender<Vec<u8>>, Receiver<Vec<u8>>)>,
+    robot_heading: Option<RobotHeading>,
+    supervisor: Supervisor,
+    registry: Arc<Registry>,
+    presets: Arc<PresetSelection>,
+    snapshots: Arc<Snapshots>,
+    running: tokio::sync::Mutex<Option<Running>>,
+}
+
+impl PipelineRuntime {
+    pub fn new(config_content: String) -> serde_json::Result<Self> {
+        let config = Config::parse(&config_content)?;
+        Ok(Self {
+            camera_names: config
+                .cameras
+                .iter()
+                .map(|x| x.camera_name.clone())
+                .collect(),
+            streams: config
+                .cameras
+                .iter()
+                .map(|_| crossbeam_channel::bounded(2))
+                .collect(),
+            robot_heading: config
+                .robot_heading
+                .as_ref()
+                .map(|x| RobotHeading::new(Duration::from_millis(x.max_age_ms))),
+            supervisor: Supervisor::default(),
+            registry: Arc::new(Registry::default()),
+            presets: Arc::new(PresetSelection::new(&config)),
+            snapshots: Arc::new(Snapshots::new(
+                config.snapshot_dir.clone().into(),
+                config.cameras.len(),
+            )),
+            running: tokio::sync::Mutex::new(None),
+            config_content,
+        })
+    }
+
+    pub fn supervisor(&self) -> &Supervisor {
+        &self.supervisor
+    }
+
+    pub fn presets(&self) -> &PresetSelection {
+        &self.presets
+    }
+
+    pub fn snapshots(&self) -> &Snapshots {
+        &self.snapshots
+    }
+
+    pub fn camera_index(&self, camera_name: &str) -> Option<usize> {
+        self.camera_names.iter().position(|x| x == camera_name)
+    }
+
+    /// MJPEG frames of each camera, keyed by camera name
+    pub fn streams(&self) -> HashMap<String, Receiver<Vec<u8>>> {
+        self.camera_names
+            .iter()
+            .cloned()
+            .zip(self.streams.iter().map(|(_, recv)| recv.clone()))
+            .collect()
+    }
+
+    /// Starts every stage. Does nothing if the pipeline is already running. Must be called from within tokio.
+    pub async fn start(&self) {
+        let mut running = self.running.lock().await;
+        if running.is_some() {
+            return;
+        }
+        // Parsed successfully in `new`
+        let config = Config::parse(&self.config_content).unwrap();
+        let context = StageContext {
+            config_content: self.config_content.clone(),
+            stop: Arc::new(AtomicBool::new(false)),
+            clock: NtClock::default(),
+            supervisor: self.supervisor.clone(),
+            robot_heading: self.robot_heading.clone(),
+            registry: self.registry.clone(),
+            presets: self.presets.clone(),
+            snapshots: self.snapshots.clone(),
+        };
+
+        // Room for a couple of frames worth of messages from every camera
+        let (data_send, data_recv) = crossbeam_channel::bounded(16 * config.cameras.len() + 1);
+        let (fusion_send, fusion_recv) = crossbeam_channel::bounded(2 * config.cameras.len());
+        let mut threads = Vec::new();
+        if config.fusion.is_some() {
+            let context = context.clone();
+            let data_send = data_send.clone();
+            threads.push(std::thread::spawn(move || {
+                fusion_thread(context, data_send, fusion_recv)
+            }));
+        }
+        for (camera, camera_config) in config.cameras.iter().enumerate() {
+            let context = context.clone();
+            let data_send = data_send.clone();
+            let send = self.streams[camera].0.clone();
+            let fusion_send = Some(fusion_send.clone())
+                .filter(|_| config.fusion.is_some() && camera_config.robot_to_camera.is_some());
+            threads.push(std::thread::spawn(move || {
+                apriltag_thread(camera, context, data_send, send, fusion_send)
+            }));
+        }
+
+        let nt_context = context.clone();
+        let camera_names = self.camera_names.clone();
+        let nt_task = tokio::spawn(async move {
+            while !nt_context.stopped() {
+                if let Err(e) = nt_thread(&nt_context, &data_recv).await {
+                    if nt_context.stopped() {
+                        break;
+                    }
+                    let counter = if e.is::<NtConnectError>() {
+                        &METRICS.nt_connect_failures
+                    } else {
+                        &METRICS.nt_errors
+                    };
+                    for name in &camera_names {
+                        counter.with_label_values(&[name]).inc();
+                    }
+                    nt_context.supervisor.report("networktables", e);
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                }
+            }
+        });
+
+        *running = Some(Running {
+            context,
+            threads,
+            nt_task,
+        });
+    }
+
+    /// Stops every stage and waits for them to finish. Does nothing if the pipeline is not running.
+    pub async fn stop(&self) {
+        let Some(running) = self.running.lock().await.take() else {
+            return;
+        };
+        running.context.stop.store(true, Ordering::Relaxed);
+        let supervisor = self.supervisor.clone();
+        let joined = tokio::task::spawn_blocking(move || {
+            for thread in running.threads {
+                if thread.join().is_err() {
+                    supervisor.report("runtime", "a pipeline thread panicked while stopping");
+                }
+            }
+        })
+        .await;
+        if let Err(e) = joined {
+            self.supervisor.report("runtime", e);
+        }
+        // Exits on its own within one receive timeout once it sees the stop flag
+        if let Err(e) = running.nt_task.await {
+            self.supervisor.report("networktables", e);
+        }
+    }
+
+    pub async fn restart(&self) {
+        self.stop().await;
+        self.start().await;
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crossbeam_channel::{Receiver, Sender};
+use nalgebra::Isometry3;
+use opencv::{core::Mat, imgcodecs, prelude::*, types::VectorOfu8};
+use parking_lot::Mutex;
+use serde_json::json;
+
+use crate::pipeline::{output::FrameResult, stream::annotate};
+
+/// What was asked of the current frame of a camera.
+pub struct SnapshotRequest {
+    /// Replies to `/snapshot.jpg`, and whether each wants the annotated frame
+    replies: Vec<(bool, Sender<Vec<u8>>)>,
+    /// Save the frame to disk
+    save: bool,
+}
+
+/// Frames asked for over HTTP or NetworkTables. Each camera pipeline checks for requests every frame.
+#[derive(Debug)]
+pub struct Snapshots {
+    directory: PathBuf,
+    replies: Vec<Mutex<Vec<(bool, Sender<Vec<u8>>)>>>,
+    save: Vec<AtomicBool>,
+}
+
+impl Snapshots {
+    pub fn new(directory: PathBuf, cameras: usize) -> Self {
+        Self {
+            directory,
+            replies: (0..cameras).map(|_| Mutex::new(Vec::new())).collect(),
+            save: (0..cameras).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    /// Where saved frames go
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// The next frame of `camera` as a JPEG, `None` if there is no such camera
+    pub fn request(&self, camera: usize, annotated: bool) -> Option<Receiver<Vec<u8>>> {
+        let (send, recv) = crossbeam_channel::bounded(1);
+        self.replies.get(camera)?.lock().push((annotated, send));
+        Some(recv)
+    }
+
+    /// Saves the next frame of `camera` with its results, see [`Self::fulfill`]
+    pub fn save(&self, camera: usize) {
+        if let Some(save) = self.save.get(camera) {
+            save.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Everything asked of the current frame of `camera`, `None` if nothing was
+    pub fn take(&self, camera: usize) -> Option<SnapshotRequest> {
+        let replies = std::mem::take(&mut *self.replies[camera].lock());
+        let save = self.save[camera].swap(false, Ordering::Relaxed);
+        (save || !replies.is_empty()).then_some(SnapshotRequest { replies, save })
+    }
+
+    /// Answers `request` with `frame`. Saved frames are written as a lossless PNG, which the `test` capture can replay,
+    /// next to a JSON file of the same name with what the pipeline found in it. Returns whether a frame was saved.
+    pub fn fulfill(
+        &self,
+        request: SnapshotRequest,
+        frame: &Mat,
+        result: &FrameResult,
+        fps: f64,
+    ) -> anyhow::Result<bool> {
+        let jpeg = |image: &Mat| -> opencv::Result<Vec<u8>> {
+            let mut data = VectorOfu8::new();
+            imgcodecs::imencode_def(".jpg", image, &mut data)?;
+            Ok(data.to_vec())
+        };
+        let raw = if request.replies.iter().any(|x| !x.0) {
+            Some(jpeg(frame)?)
+        } else {
+            None
+        };
+        let annotated = if request.replies.iter().any(|x| x.0) {
+            let mut image = frame.try_clone()?;
+            annotate(&mut image, result, fps)?;
+            Some(jpeg(&image)?)
+        } else {
+            None
+        };
+        for (wants_annotated, reply) in request.replies {
+            let data = if wants_annotated { &annotated } else { &raw };
+            if let Some(data) = data {
+                _ = reply.try_send(data.clone());
+            }
+        }
+
+        if request.save {
+            std::fs::create_dir_all(&self.directory)?;
+            let name = format!(
+                "{}-{}",
+                result.config.camera_name,
+                SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis()
+            );
+            let image_path = self.directory.join(format!("{}.png", name));
+            if !imgcodecs::imwrite_def(&image_path.to_string_lossy(), frame)? {
+                anyhow::bail!("failed to write {}", image_path.display());
+            }
+            // Absolute so the frame can be replayed from any working directory
+            let image_path = std::fs::canonicalize(&image_path)?;
+            std::fs::write(
+                self.directory.join(format!("{}.json", name)),
+                serde_json::to_string_pretty(&sidecar(result, &image_path))?,
+            )?;
+        }
+        Ok(request.save)
+    }
+
+    /// Names of the saved files, newest first
+    pub fn list(&self) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(&self.directory) else {
+            return Vec::new();
+        };
+        let mut files = entries
+            .filter_map(|x| x.ok())
+            .filter_map(|x| {
+                let modified = x.metadata().ok()?.modified().ok()?;
+                Some((modified, x.file_name().into_string().ok()?))
+            })
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| b.cmp(a));
+        files.into_iter().map(|(_, name)| name).collect()
+    }
+}
+
+/// Same format as the poses in the tag layout
+fn isometry_json(pose: &Isometry3<f64>) -> serde_json::Value {
+    let translation = pose.translation.vector;
+    let rotation = pose.rotation.quaternion();
+    json!({
+        "translation": { "x": translation.x, "y": translation.y, "z": translation.z },
+        "rotation": {
+            "quaternion": { "W": rotation.w, "X": rotation.i, "Y": rotation.j, "Z": rotation.k },
+        },
+    })
+}
+
+fn sidecar(result: &FrameResult, image_path: &Path) -> serde_json::Value {
+    json!({
+        "camera": result.config.camera_name,
+        "pipeline": result.config.name,
+        "time": result.time,
+        // Capture stage that replays this frame
+        "capture": { "backend": "test", "path": image_path.to_string_lossy() },
+        "detections": result
+            .observations
+            .iter()
+            .map(|x| json!({ "id": x.tag_id, "corners": x.corners, "quality": x.quality }))
+            .collect::<Vec<_>>(),
+        "targets": result
+            .targets
+            .iter()
+            .map(|x| json!({
+                "id": x.tag_id,
+                "yaw": x.yaw,
+                "pitch": x.pitch,
+                "area": x.area,
+                "skew": x.skew,
+            }))
+            .collect::<Vec<_>>(),
+        "objects": result.objects.map(|objects| {
+            objects
+                .iter()
+                .map(|x| json!({
+                    "class_id": x.class_id,
+                    "class_name": x.class_name,
+                    "confidence": x.confidence,
+                    "yaw": x.yaw,
+                    "pitch": x.pitch,
+                    "area": x.area,
+                    "bounding_box": x.bounding_box,
+                    "position": x.position,
+                    "corners": x.corners,
+                    "camera_to_target": x.camera_to_target.as_ref().map(isometry_json),
+                }))
+                .collect::<Vec<_>>()
+        }),
+        "pose": result.pose.map(|pose| json!({
+            "pose": isometry_json(&pose.pose_0),
+            "error": pose.error_0,
+            "ambiguous_pose": pose.pose_1.as_ref().map(isometry_json),
+            "ambiguous_error": pose.error_1,
+            "tag_ids": pose.tag_ids,
+            "rejection": pose.rejection.as_ref().map(|x| x.to_string()),
+        })),
+        "filtered_pose": result.filtered_pose.map(|pose| isometry_json(&pose.pose_0)),
+    })
+}
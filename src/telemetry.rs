@@ -0,0 +1,187 @@
+use crate::nt::{self, PublishProperties};
+
+/// Health information about a camera pipeline, sent to NetworkTables at most once a second. The active pipeline is
+/// also sent as soon as it changes, see [`TelemetryPublisher::publish_pipeline`].
+#[derive(Debug, Clone, Default)]
+pub struct PipelineTelemetry {
+    /// Incremented for every frame that makes it through the pipeline. Stops changing when the camera freezes.
+    pub heartbeat: u64,
+    pub fps: f64,
+    pub capture_latency_ms: f64,
+    pub detect_latency_ms: f64,
+    pub solve_latency_ms: f64,
+    pub encode_latency_ms: f64,
+    /// Frames the capture failed to deliver since the pipeline started.
+    pub dropped_frames: u64,
+    pub tags_seen: u64,
+    /// Poses dropped by the pose filter since the pipeline started
+    pub rejected_poses: u64,
+    pub last_rejection: Option<String>,
+    /// Most recent error of any pipeline stage
+    pub last_error: Option<String>,
+    /// Index of the active pipeline configuration, see [`crate::runtime::PresetSelection`]
+    pub pipeline: u64,
+    pub pipeline_name: String,
+    /// Degrees celsius
+    pub cpu_temperature: Option<f64>,
+    /// One minute load average
+    pub cpu_load: Option<f64>,
+}
+
+impl PipelineTelemetry {
+    pub fn sample_system(&mut self) {
+        self.cpu_temperature = cpu_temperature();
+        self.cpu_load = cpu_load();
+    }
+}
+
+fn cpu_temperature() -> Option<f64> {
+    let millidegrees = std::fs::read_to_string("/sys/class/thermal/thermal_zone0/temp").ok()?;
+    Some(millidegrees.trim().parse::<f64>().ok()? / 1000.0)
+}
+
+fn cpu_load() -> Option<f64> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+/// Topics under `/watson/<camera>/` that [`PipelineTelemetry`] is published to.
+pub struct TelemetryPublisher {
+    heartbeat: nt::PublishedTopic,
+    fps: nt::PublishedTopic,
+    capture_latency_ms: nt::PublishedTopic,
+    detect_latency_ms: nt::PublishedTopic,
+    solve_latency_ms: nt::PublishedTopic,
+    encode_latency_ms: nt::PublishedTopic,
+    dropped_frames: nt::PublishedTopic,
+    tags_seen: nt::PublishedTopic,
+    rejected_poses: nt::PublishedTopic,
+    last_rejection: nt::PublishedTopic,
+    last_error: nt::PublishedTopic,
+    pipeline: nt::PublishedTopic,
+    pipeline_name: nt::PublishedTopic,
+    cpu_temperature: nt::PublishedTopic,
+    cpu_load: nt::PublishedTopic,
+}
+
+impl TelemetryPublisher {
+    pub async fn new(client: &nt::Client, camera_name: &str) -> nt::Result<Self> {
+        let publish = |name: &str, r#type: nt::Type| {
+            client.publish_topic(
+                format!("/watson/{}/{}", camera_name, name),
+                r#type,
+                Some(PublishProperties {
+                    persistent: Some(false),
+                    retained: Some(false),
+                    rest: None,
+                }),
+            )
+        };
+        Ok(Self {
+            heartbeat: publish("heartbeat", nt::Type::Int).await?,
+            fps: publish("fps", nt::Type::Double).await?,
+            capture_latency_ms: publish("capture_latency_ms", nt::Type::Double).await?,
+            detect_latency_ms: publish("detect_latency_ms", nt::Type::Double).await?,
+            solve_latency_ms: publish("solve_latency_ms", nt::Type::Double).await?,
+            encode_latency_ms: publish("encode_latency_ms", nt::Type::Double).await?,
+            dropped_frames: publish("dropped_frames", nt::Type::Int).await?,
+            tags_seen: publish("tags_seen", nt::Type::Int).await?,
+            rejected_poses: publish("rejected_poses", nt::Type::Int).await?,
+            last_rejection: publish("last_rejection", nt::Type::String).await?,
+            last_error: publish("last_error", nt::Type::String).await?,
+            pipeline: publish("pipeline", nt::Type::Int).await?,
+            pipeline_name: publish("pipeline_name", nt::Type::String).await?,
+            cpu_temperature: publish("cpu_temperature", nt::Type::Double).await?,
+            cpu_load: publish("cpu_load", nt::Type::Double).await?,
+        })
+    }
+
+    pub async fn publish(
+        &self,
+        client: &nt::Client,
+        telemetry: &PipelineTelemetry,
+    ) -> nt::Result<()> {
+        use rmpv::Value;
+
+        client
+            .publish_value(&self.heartbeat, &Value::from(telemetry.heartbeat))
+            .await?;
+        client
+            .publish_value(&self.fps, &Value::F64(telemetry.fps))
+            .await?;
+        client
+            .publish_value(
+                &self.capture_latency_ms,
+                &Value::F64(telemetry.capture_latency_ms),
+            )
+            .await?;
+        client
+            .publish_value(
+                &self.detect_latency_ms,
+                &Value::F64(telemetry.detect_latency_ms),
+            )
+            .await?;
+        client
+            .publish_value(
+                &self.solve_latency_ms,
+                &Value::F64(telemetry.solve_latency_ms),
+            )
+            .await?;
+        client
+            .publish_value(
+                &self.encode_latency_ms,
+                &Value::F64(telemetry.encode_latency_ms),
+            )
+            .await?;
+        client
+            .publish_value(&self.dropped_frames, &Value::from(telemetry.dropped_frames))
+            .await?;
+        client
+            .publish_value(&self.tags_seen, &Value::from(telemetry.tags_seen))
+            .await?;
+        client
+            .publish_value(&self.rejected_poses, &Value::from(telemetry.rejected_poses))
+            .await?;
+        if let Some(last_rejection) = &telemetry.last_rejection {
+            client
+                .publish_value(&self.last_rejection, &Value::from(last_rejection.as_str()))
+                .await?;
+        }
+        self.publish_pipeline(client, telemetry.pipeline, &telemetry.pipeline_name)
+            .await?;
+        if let Some(last_error) = &telemetry.last_error {
+            client
+                .publish_value(&self.last_error, &Value::from(last_error.as_str()))
+                .await?;
+        }
+        if let Some(cpu_temperature) = telemetry.cpu_temperature {
+            client
+                .publish_value(&self.cpu_temperature, &Value::F64(cpu_temperature))
+                .await?;
+        }
+        if let Some(cpu_load) = telemetry.cpu_load {
+            client
+                .publish_value(&self.cpu_load, &Value::F64(cpu_load))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Publishes only the active pipeline, which acknowledges a switch
+    pub async fn publish_pipeline(
+        &self,
+        client: &nt::Client,
+        pipeline: u64,
+        pipeline_name: &str,
+    ) -> nt::Result<()> {
+        use rmpv::Value;
+
+        client
+            .publish_value(&self.pipeline, &Value::from(pipeline))
+            .await?;
+        client
+            .publish_value(&self.pipeline_name, &Value::from(pipeline_name))
+            .await?;
+        Ok(())
+    }
+}
@@ -1,12 +1,16 @@
-use binrw::BinWrite;
-use nalgebra::{Isometry3, Vector3};
+use nalgebra::{Isometry3, Matrix6, Vector3};
 use opencv::core::VecN;
 
+use crate::pipeline::pose_filter::PoseRejection;
 
-#[derive(Debug)]
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct FiducialImageObservation {
     pub tag_id: u64,
     pub corners: [[f64; 2]; 4],
+    /// How confident the detector is in the decode, higher is better. `None` if the detector does not say. For ArUco
+    /// this is the contrast across the edges of the tag, from 0 to 1.
+    pub quality: Option<f64>,
 }
 
 #[derive(Debug)]
@@ -25,50 +29,16 @@ pub struct CameraPoseObservation {
     pub error_0: f64,
     pub pose_1: Option<Isometry3<f64>>,
     pub error_1: Option<f64>,
-}
-
-impl BinWrite for CameraPoseObservation {
-    type Args<'a> = (u32,);
-
-    fn write_options<W: std::io::prelude::Write + std::io::prelude::Seek>(
-        &self,
-        writer: &mut W,
-        _endian: binrw::Endian,
-        (time,): Self::Args<'_>,
-    ) -> binrw::prelude::BinResult<()> {
-        time.write_be(writer)?;
-        (self.tag_ids.len() as i32).write_be(writer)?;
-        for tag in &self.tag_ids {
-            (*tag as i32).write_be(writer)?;
-        }
-        if let Some(_) = self.pose_1.as_ref() {
-            1u8.write_be(writer)?;
-        } else {
-            0u8.write_be(writer)?;
-        }
-
-        self.pose_0.translation.x.write_be(writer)?;
-        self.pose_0.translation.y.write_be(writer)?;
-        self.pose_0.translation.z.write_be(writer)?;
-        self.pose_0.rotation.w.write_be(writer)?;
-        self.pose_0.rotation.vector().x.write_be(writer)?;
-        self.pose_0.rotation.vector().y.write_be(writer)?;
-        self.pose_0.rotation.vector().z.write_be(writer)?;
-        self.error_0.write_be(writer)?;
-        
-        if let Some(pose_1) = self.pose_1.as_ref() {
-            pose_1.translation.x.write_be(writer)?;
-            pose_1.translation.y.write_be(writer)?;
-            pose_1.translation.z.write_be(writer)?;
-            pose_1.rotation.w.write_be(writer)?;
-            pose_1.rotation.vector().x.write_be(writer)?;
-            pose_1.rotation.vector().y.write_be(writer)?;
-            pose_1.rotation.vector().z.write_be(writer)?;
-            self.error_1.as_ref().unwrap().write_be(writer)?;
-        }
-        
-        Ok(())
-    }
+    /// Reprojection error in pixels of each tag in `tag_ids` against `pose_0`
+    pub tag_residuals: Vec<f64>,
+    /// Tags that were seen but left out of the solve as outliers
+    pub rejected_tag_ids: Vec<u64>,
+    /// Covariance of `pose_0` when it was refined, see [`crate::pipeline::reprojection::Refinement`]
+    pub covariance: Option<Matrix6<f64>>,
+    /// Standard deviations of x, y, z in meters and roll, pitch, yaw in radians
+    pub std_devs: [f64; 6],
+    /// Set when the pose was found implausible but is published anyway
+    pub rejection: Option<PoseRejection>,
 }
 
 pub fn isometry_from_opencv(t: VecN<f64, 3>, r: VecN<f64, 3>) -> Isometry3<f64> {